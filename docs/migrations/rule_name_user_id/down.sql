@@ -0,0 +1,5 @@
+DROP INDEX public.rule_user_id_name_key;
+
+ALTER TABLE public.rule
+    DROP COLUMN user_id,
+    DROP COLUMN name;
@@ -0,0 +1,7 @@
+-- Rules with a name are libraries that other rules of the same user can IMPORT, the name
+-- has to be unique per user.
+ALTER TABLE public.rule
+    ADD COLUMN name text,
+    ADD COLUMN user_id uuid REFERENCES public.users (id) ON UPDATE CASCADE ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX rule_user_id_name_key ON public.rule (user_id, name) WHERE name IS NOT NULL;
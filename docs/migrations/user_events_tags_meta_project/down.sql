@@ -0,0 +1,6 @@
+DROP INDEX public.user_events_project_id_idx;
+
+ALTER TABLE public.user_events
+    DROP COLUMN project_id,
+    DROP COLUMN meta,
+    DROP COLUMN tags;
@@ -0,0 +1,8 @@
+-- Tags and metadata set with TAG and SET_META, and the project picked with ASSIGN_PROJECT.
+-- Events without a project keep the project of their rule.
+ALTER TABLE public.user_events
+    ADD COLUMN tags jsonb NOT NULL DEFAULT '[]'::jsonb,
+    ADD COLUMN meta jsonb NOT NULL DEFAULT '{}'::jsonb,
+    ADD COLUMN project_id uuid REFERENCES public.projects (id) ON UPDATE CASCADE ON DELETE SET NULL;
+
+CREATE INDEX user_events_project_id_idx ON public.user_events (project_id);
@@ -1,15 +1,23 @@
 #[macro_use]
 extern crate log;
-use rustc_hash::FxHashMap;
-use std::{sync::Arc, thread, time::Duration};
+use std::{sync::Arc, thread};
 use timetrackrs::{
-    capture::capture_peripherals, graphql::get_user_rules, scripting::*, util::get_os_info,
+    capture::capture_peripherals,
+    graphql::{get_user_rules, UserRules},
+    scripting::*,
+    util::get_os_info,
 };
 
 fn main() {
     env_logger::init();
     capture_peripherals();
-    let rules = get_user_rules().expect("Couldn't get Rules");
+    let UserRules {
+        rules,
+        projects,
+        libraries,
+    } = get_user_rules().expect("Couldn't get Rules");
+    // Shared by all rules, so that every library is only parsed once.
+    let mut libraries = Libraries::new(libraries);
     let os_info = get_os_info();
 
     let os_type = Arc::new(os_info.os_type);
@@ -18,60 +26,69 @@ fn main() {
     let hostname = Arc::new(os_info.hostname);
     let username = os_info.username.map(|s| Arc::new(s));
     let machine_id = os_info.machine_id.map(|s| Arc::new(s));
+    let project_variables: Vec<VariableMapType> = projects
+        .iter()
+        .map(|project| {
+            let mut map = VariableMapType::default();
+            map.insert("ID".into(), project.id.clone().into());
+            map.insert("NAME".into(), project.name.clone().into());
+            map
+        })
+        .collect();
 
-    let mut join_handles = vec![];
-
+    let mut programs = vec![];
     for (project_rule_id, rule_body) in rules {
-        let os_type = os_type.clone();
-        let version = version.clone();
-        let batteries = batteries.clone();
-        let hostname = hostname.clone();
-        let username = username.clone();
-        let machine_id = machine_id.clone();
+        let (program, diagnostics) = parse_with_projects(&rule_body, &mut libraries, &projects);
+        for diagnostic in &diagnostics {
+            let rendered = diagnostic.render(&rule_body, &project_rule_id);
+            match diagnostic.severity {
+                Severity::Error => error!("{}", rendered),
+                Severity::Warning => warn!("{}", rendered),
+            }
+        }
+        match program {
+            Some(program) => programs.push((
+                Rule {
+                    id: project_rule_id,
+                    body: rule_body,
+                },
+                program,
+            )),
+            None => error!("Couldn't parse the body of rule {}", project_rule_id),
+        }
+    }
 
-        let handle = thread::spawn(move || {
-            // variable_map, must always outlive executables, if it's dropped any earlier it'll cause
-            // undefined behaviour, because only a raw pointer is passed to the executables
-            let mut variable_map: VariableMapType = FxHashMap::default();
-            let (timeout_duration, mut executables) =
-                parse(&rule_body, &mut variable_map as *mut _).expect("Couldn't Parse Body");
-            variable_map.insert("RULE_ID", project_rule_id.into());
-            variable_map.insert("RULE_BODY", rule_body.into());
-            variable_map.insert("OS_TYPE", os_type.into());
-            variable_map.insert("VERSION", version.into());
+    // All rules run on one thread, the main thread is needed on macOS.
+    let scheduler_thread = thread::spawn(move || {
+        let mut scheduler = Scheduler::new();
+        for (rule, program) in programs {
+            let mut context = ExecutionContext::new(rule, scheduler.capturer());
+            let variable_map = &mut context.variables;
+            variable_map.insert("OS_TYPE".into(), os_type.clone().into());
+            variable_map.insert("VERSION".into(), version.clone().into());
             if let Some(batteries) = batteries {
-                variable_map.insert("BATTERIES", (batteries as usize).into());
-            }
-            variable_map.insert("HOSTNAME", hostname.into());
-            if let Some(username) = username {
-                variable_map.insert("USERNAME", username.into());
+                variable_map.insert("BATTERIES".into(), (batteries as usize).into());
             }
-            if let Some(machine_id) = machine_id {
-                variable_map.insert("MACHINE_ID", machine_id.into());
+            variable_map.insert("HOSTNAME".into(), hostname.clone().into());
+            if let Some(username) = &username {
+                variable_map.insert("USERNAME".into(), username.clone().into());
             }
-            loop {
-                thread::sleep(timeout_duration);
-                for executable in &mut executables {
-                    if let Err(err) = executable.execute() {
-                        error!("{}", err);
-                    }
-                }
+            if let Some(machine_id) = &machine_id {
+                variable_map.insert("MACHINE_ID".into(), machine_id.clone().into());
             }
-        });
-        join_handles.push(handle);
-    }
-
-    let join_thread = thread::spawn(move || {
-        for handle in join_handles {
-            handle.join().unwrap();
+            variable_map.insert("PROJECTS".into(), project_variables.clone().into());
+            context.projects = projects.clone();
+            scheduler.add(Interpreter::new(program), context);
         }
+        scheduler.run();
     });
 
     #[cfg(target_os = "macos")]
     {
         // Loop for updating the frontmostApplication PID
+        use std::time::Duration;
         use timetrackrs::capture::macos::appkit::update_frontmost_application_pid;
-        while !join_thread.is_finished() {
+        while !scheduler_thread.is_finished() {
             unsafe {
                 update_frontmost_application_pid();
             }
@@ -79,5 +96,5 @@ fn main() {
         }
     }
 
-    join_thread.join().unwrap();
+    scheduler_thread.join().unwrap();
 }
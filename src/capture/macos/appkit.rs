@@ -196,10 +196,13 @@ impl Capturer for MacOSCapturer {
         Ok(Event {
             windows,
             rule: None,
+            project: None,
             keyboard: 0,
             mouse: 0,
             screenshots: None,
             network: None,
+            tags: vec![],
+            meta: Default::default(),
             seconds_since_last_input: user_idle::UserIdle::get_time()
                 .map(|e| e.duration())
                 .map_err(|e| anyhow::Error::msg(e))
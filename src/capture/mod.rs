@@ -1,9 +1,13 @@
 pub mod linux;
 pub mod macos;
 pub mod pc_common;
+pub mod triggers;
 pub mod windows;
 
-use std::thread;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
 
 #[cfg(target_os = "macos")]
 pub fn create_capturer() -> Box<dyn Capturer> {
@@ -36,3 +40,37 @@ pub fn create_capturer() -> Box<dyn Capturer> {
 pub trait Capturer: Send {
     fn capture(&mut self) -> anyhow::Result<pc_common::Event>;
 }
+
+/// One capturer for all rules, the rules that run in the same tick get the same capture
+/// instead of each capturing the windows on its own.
+#[derive(Default)]
+pub struct SharedCapturer {
+    /// Created by the first capture.
+    capturer: Option<Box<dyn Capturer>>,
+    /// The capture of the current tick.
+    snapshot: Option<pc_common::Event>,
+}
+
+impl SharedCapturer {
+    pub fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Forgets the capture of the last tick, the next `capture` captures again.
+    pub fn tick(&mut self) {
+        self.snapshot = None;
+    }
+
+    /// The windows as they are in the current tick, captured by the first rule that asks.
+    pub fn capture(&mut self) -> anyhow::Result<pc_common::Event> {
+        if let Some(snapshot) = &self.snapshot {
+            return Ok(snapshot.clone());
+        }
+        let event = self
+            .capturer
+            .get_or_insert_with(create_capturer)
+            .capture()?;
+        self.snapshot = Some(event.clone());
+        Ok(event)
+    }
+}
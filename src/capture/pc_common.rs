@@ -4,7 +4,8 @@ use crate::{
     capture,
     scripting::{Rule, Variable, VariableMapType},
 };
-use serde_json::Value;
+use chrono::{Duration, Utc};
+use serde_json::{Map, Value};
 use std::{convert::TryFrom, sync::atomic::AtomicUsize};
 use sysinfo::ProcessExt;
 
@@ -28,11 +29,21 @@ pub struct Event {
     pub screenshots: Option<Box<Vec<Value>>>,
     #[serde(rename = "projectRuleId")]
     pub rule: Option<Rule>,
+    /// Set by the rule with `ASSIGN_PROJECT`, otherwise the event belongs to the project
+    /// of the rule.
+    #[serde(rename = "projectId", default)]
+    pub project: Option<String>,
     #[serde(rename = "ssidId")]
     pub network: Option<String>,
     pub keyboard: usize,
     pub mouse: usize,
     pub seconds_since_last_input: u64,
+    /// `category:value` pairs added by the rule with `TAG`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Added by the rule with `SET_META`.
+    #[serde(default)]
+    pub meta: Map<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,19 +58,25 @@ impl From<Window> for VariableMapType {
         let mut map = Self::default();
 
         if let Some(title) = window.title {
-            map.insert("TITLE", title.into());
+            map.insert("TITLE".into(), title.into());
         }
 
-        map.insert("PROCESS_NAME", window.process.name.into());
-        map.insert("CMD", window.process.cmd.into());
-        map.insert("EXE", window.process.exe.into());
-        map.insert("CWD", window.process.cwd.into());
-        map.insert("MEMORY", (window.process.memory as usize).into());
-        map.insert("STATUS", window.process.status.into());
-        map.insert("START_TIME", window.process.start_time.into());
+        map.insert("PROCESS_NAME".into(), window.process.name.into());
+        map.insert("CMD".into(), window.process.cmd.into());
+        map.insert("EXE".into(), window.process.exe.into());
+        map.insert("CWD".into(), window.process.cwd.into());
+        map.insert("MEMORY".into(), (window.process.memory as usize).into());
+        map.insert("STATUS".into(), window.process.status.into());
+        map.insert("START_TIME".into(), window.process.start_time.into());
+        // The start time is in seconds since the epoch.
+        let uptime = (Utc::now().timestamp() as u64).saturating_sub(window.process.start_time);
+        map.insert(
+            "UPTIME_OF_PROCESS".into(),
+            Duration::seconds(uptime as i64).into(),
+        );
 
         if let Some(cpu_usage) = window.process.cpu_usage {
-            map.insert("CPU_USAGE", cpu_usage.into());
+            map.insert("CPU_USAGE".into(), cpu_usage.into());
         }
         map
     }
@@ -69,32 +86,32 @@ impl TryFrom<&VariableMapType> for Window {
     type Error = anyhow::Error;
     fn try_from(variable_map: &VariableMapType) -> Result<Self, Self::Error> {
         let title: Option<String> = match variable_map.get("TITLE") {
-            Some(Variable::RcStr(string)) => Some((**string).clone()),
+            Some(Variable::ArcStr(string)) => Some((**string).clone()),
             None => None,
             _ => anyhow::bail!("TITLE is not a String"),
         };
         let name = match variable_map.get("PROCESS_NAME") {
-            Some(Variable::RcStr(string)) => (**string).clone(),
+            Some(Variable::ArcStr(string)) => (**string).clone(),
             _ => anyhow::bail!("NAME is not a String"),
         };
         let cmd = match variable_map.get("CMD") {
-            Some(Variable::RcStr(string)) => (**string).clone(),
+            Some(Variable::ArcStr(string)) => (**string).clone(),
             _ => anyhow::bail!("CMD is not a String"),
         };
         let exe = match variable_map.get("EXE") {
-            Some(Variable::RcStr(string)) => (**string).clone(),
+            Some(Variable::ArcStr(string)) => (**string).clone(),
             _ => anyhow::bail!("EXE is not a String"),
         };
         let cwd = match variable_map.get("CWD") {
-            Some(Variable::RcStr(string)) => (**string).clone(),
+            Some(Variable::ArcStr(string)) => (**string).clone(),
             _ => anyhow::bail!("CWD is not a String"),
         };
         let memory = match variable_map.get("MEMORY") {
-            Some(Variable::Int(int)) => *int as i64,
+            Some(Variable::Int(int)) => *int,
             _ => anyhow::bail!("MEMORY is not an Int"),
         };
         let status = match variable_map.get("STATUS") {
-            Some(Variable::RcStr(string)) => (**string).clone(),
+            Some(Variable::ArcStr(string)) => (**string).clone(),
             _ => anyhow::bail!("STATUS is not a String"),
         };
         let start_time = match variable_map.get("START_TIME") {
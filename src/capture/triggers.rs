@@ -0,0 +1,197 @@
+use super::pc_common::{get_network_ssid, Event};
+use crate::{config::CONFIG, scripting::Trigger};
+use rustc_hash::FxHashSet;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the computer is checked for changes while a rule waits for a trigger.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the network is looked up while a rule waits for `NETWORK_CHANGE`.
+const NETWORK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// If a wait takes this much longer than planned, the computer was asleep in between.
+const SLEEP_GAP: Duration = Duration::from_secs(30);
+
+/// Tells from the captures of the scheduler which triggers fired. It only looks at what the
+/// rules wait for, e.g. the network is never looked up without a `NETWORK_CHANGE` block.
+#[derive(Debug, Default)]
+pub struct Watcher {
+    watched: FxHashSet<Trigger>,
+    /// The title, the process name and the start time tell windows apart well enough.
+    focused: Option<(Option<String>, String, u64)>,
+    idle: bool,
+    /// The network of the last lookup and when it happened.
+    network: Option<(Option<String>, Instant)>,
+}
+
+impl Watcher {
+    /// Watches for `triggers` in addition to the ones watched already.
+    pub fn watch(&mut self, triggers: impl IntoIterator<Item = Trigger>) {
+        self.watched.extend(triggers);
+    }
+
+    /// Whether the next `check` needs a capture of the windows.
+    pub fn needs_capture(&self) -> bool {
+        [Trigger::WindowChange, Trigger::IdleStart, Trigger::IdleEnd]
+            .iter()
+            .any(|trigger| self.watched.contains(trigger))
+    }
+
+    /// The triggers that fired since the last check. `event` is the capture of the current
+    /// tick, if there is one. The scheduler started to wait for this check at `since` and
+    /// meant to wait for `wait`.
+    pub fn check(
+        &mut self,
+        event: Option<&Event>,
+        wait: Duration,
+        since: SystemTime,
+    ) -> Vec<Trigger> {
+        let mut fired = vec![];
+
+        // The monotonic clock stops while the computer sleeps, the wall clock doesn't.
+        if let Ok(elapsed) = SystemTime::now().duration_since(since) {
+            if elapsed > wait + SLEEP_GAP {
+                fired.push(Trigger::Resume);
+            }
+        }
+
+        if let Some(event) = event {
+            let window = event.windows.first().map(|window| {
+                (
+                    window.title.clone(),
+                    window.process.name.clone(),
+                    window.process.start_time,
+                )
+            });
+            if window.is_some() && window != self.focused {
+                if self.focused.is_some() {
+                    fired.push(Trigger::WindowChange);
+                }
+                self.focused = window;
+            }
+
+            let idle = event.seconds_since_last_input >= CONFIG.triggers.idle_after_seconds;
+            if idle != self.idle {
+                self.idle = idle;
+                fired.push(if idle {
+                    Trigger::IdleStart
+                } else {
+                    Trigger::IdleEnd
+                });
+            }
+        }
+
+        if self.watched.contains(&Trigger::NetworkChange) {
+            let due = match &self.network {
+                Some((_, checked)) => checked.elapsed() >= NETWORK_INTERVAL,
+                None => true,
+            };
+            if due {
+                let ssid = get_network_ssid();
+                if let Some((network, _)) = self.network.replace((ssid.clone(), Instant::now())) {
+                    if network != ssid {
+                        fired.push(Trigger::NetworkChange);
+                    }
+                }
+            }
+        }
+
+        fired.retain(|trigger| self.watched.contains(trigger));
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::pc_common::{Process, Window};
+
+    fn watcher(triggers: &[Trigger]) -> Watcher {
+        let mut watcher = Watcher::default();
+        watcher.watch(triggers.iter().copied());
+        watcher
+    }
+
+    fn event(title: &str, seconds_since_last_input: u64) -> Event {
+        let process = Process {
+            name: "editor".to_owned(),
+            cmd: String::new(),
+            exe: String::new(),
+            cwd: String::new(),
+            memory: 0,
+            status: String::new(),
+            start_time: 1,
+            cpu_usage: None,
+        };
+        Event {
+            windows: vec![Window {
+                title: Some(title.to_owned()),
+                process,
+            }],
+            screenshots: None,
+            rule: None,
+            project: None,
+            network: None,
+            keyboard: 0,
+            mouse: 0,
+            seconds_since_last_input,
+            tags: vec![],
+            meta: Default::default(),
+        }
+    }
+
+    fn check(watcher: &mut Watcher, event: &Event) -> Vec<Trigger> {
+        watcher.check(Some(event), POLL_INTERVAL, SystemTime::now())
+    }
+
+    #[test]
+    fn window_change_fires_when_the_focus_moves() {
+        let mut watcher = watcher(&[Trigger::WindowChange]);
+
+        // The first capture only tells which window has the focus.
+        assert_eq!(check(&mut watcher, &event("a", 0)), []);
+        assert_eq!(check(&mut watcher, &event("a", 0)), []);
+        assert_eq!(check(&mut watcher, &event("b", 0)), [Trigger::WindowChange]);
+        assert_eq!(check(&mut watcher, &event("b", 0)), []);
+    }
+
+    #[test]
+    fn idle_start_and_end_fire_when_the_user_leaves_and_returns() {
+        let idle = CONFIG.triggers.idle_after_seconds;
+        let mut watcher = watcher(&[Trigger::IdleStart, Trigger::IdleEnd]);
+
+        assert_eq!(check(&mut watcher, &event("a", 0)), []);
+        assert_eq!(check(&mut watcher, &event("a", idle)), [Trigger::IdleStart]);
+        assert_eq!(check(&mut watcher, &event("a", idle + 1)), []);
+        assert_eq!(check(&mut watcher, &event("a", 0)), [Trigger::IdleEnd]);
+        assert_eq!(check(&mut watcher, &event("a", 0)), []);
+    }
+
+    #[test]
+    fn resume_fires_when_the_wait_took_much_longer_than_planned() {
+        let mut watcher = watcher(&[Trigger::Resume]);
+        assert!(!watcher.needs_capture());
+
+        let since = SystemTime::now() - POLL_INTERVAL;
+        assert_eq!(watcher.check(None, POLL_INTERVAL, since), []);
+
+        let since = SystemTime::now() - (POLL_INTERVAL + SLEEP_GAP + Duration::from_secs(1));
+        assert_eq!(watcher.check(None, POLL_INTERVAL, since), [Trigger::Resume]);
+    }
+
+    #[test]
+    fn only_the_watched_triggers_fire() {
+        let idle = CONFIG.triggers.idle_after_seconds;
+        let mut watcher = watcher(&[Trigger::IdleStart]);
+        assert!(watcher.needs_capture());
+
+        assert_eq!(check(&mut watcher, &event("a", 0)), []);
+        // The window changes and the computer was asleep, but only IDLE_START is watched.
+        let since = SystemTime::now() - (POLL_INTERVAL + SLEEP_GAP + Duration::from_secs(1));
+        assert_eq!(
+            watcher.check(Some(&event("b", idle)), POLL_INTERVAL, since),
+            [Trigger::IdleStart]
+        );
+        assert_eq!(check(&mut watcher, &event("c", 0)), []);
+    }
+}
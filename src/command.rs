@@ -0,0 +1,247 @@
+use crate::config::{self, Config, CONFIG};
+use rustc_hash::FxHashMap;
+use std::{
+    collections::VecDeque,
+    io::Read,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// When each command was started within the last minute, by its name.
+    static ref STARTS: Mutex<FxHashMap<String, VecDeque<Instant>>> =
+        Mutex::new(FxHashMap::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub stdout: String,
+    /// As the OS reports it, negative codes stay negative.
+    pub exit_code: i32,
+}
+
+/// Runs the command called `name` in the config of the user with `args` appended to its
+/// own arguments. Commands are started directly, never through a shell.
+pub fn run_command(name: &str, args: &[String]) -> anyhow::Result<Output> {
+    let command = allowed(&CONFIG.commands, name)?;
+    rate_limit(
+        &mut STARTS.lock().unwrap(),
+        name,
+        command.max_per_minute,
+        Instant::now(),
+    )?;
+    run(name, command, args)
+}
+
+/// The command called `name` in `commands`, an error if there's none.
+fn allowed<'a>(
+    commands: &'a FxHashMap<String, config::Command>,
+    name: &str,
+) -> anyhow::Result<&'a config::Command> {
+    commands.get(name).ok_or_else(|| {
+        anyhow!(
+            "RUN `{}` isn't allowed, the allowed commands are set in {}",
+            name,
+            Config::path().map_or_else(
+                || "config.json".to_owned(),
+                |path| path.display().to_string()
+            )
+        )
+    })
+}
+
+/// Records a start of `name` at `now` in `starts`, unless it already started
+/// `max_per_minute` times within the minute before.
+fn rate_limit(
+    starts: &mut FxHashMap<String, VecDeque<Instant>>,
+    name: &str,
+    max_per_minute: usize,
+    now: Instant,
+) -> anyhow::Result<()> {
+    let starts = starts.entry(name.to_owned()).or_default();
+    while let Some(start) = starts.front() {
+        if now.duration_since(*start) < RATE_LIMIT_WINDOW {
+            break;
+        }
+        starts.pop_front();
+    }
+    if starts.len() >= max_per_minute {
+        anyhow::bail!(
+            "RUN `{}` is rate limited, it may only run {} times a minute",
+            name,
+            max_per_minute
+        );
+    }
+    starts.push_back(now);
+    Ok(())
+}
+
+/// Runs `command` and waits for it to exit and to close its output, both within its
+/// timeout.
+fn run(name: &str, command: &config::Command, args: &[String]) -> anyhow::Result<Output> {
+    debug!("Running {} {:?} {:?}", command.program, command.args, args);
+
+    let mut child = Command::new(&command.program)
+        .args(&command.args)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| anyhow!("Couldn't start `{}`: {}", command.program, err))?;
+
+    // Read on another thread, a command that fills the pipe would never exit otherwise.
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = vec![];
+        let _ = sender.send(stdout.read_to_end(&mut output).map(|_| output));
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(command.timeout_seconds);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // The command may have exited in the meantime, then there's nothing to kill.
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!(
+                "RUN `{}` was killed after {} seconds",
+                name,
+                command.timeout_seconds
+            );
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // A process the command started in the background may keep the output open after the
+    // command exited, the reader is left behind then.
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    let stdout = match receiver.recv_timeout(timeout) {
+        Ok(Ok(stdout)) => String::from_utf8_lossy(&stdout).into_owned(),
+        Ok(Err(err)) => anyhow::bail!("Couldn't read the output of RUN `{}`: {}", name, err),
+        Err(RecvTimeoutError::Timeout) => anyhow::bail!(
+            "RUN `{}` exited, but its output was still open after {} seconds",
+            name,
+            command.timeout_seconds
+        ),
+        Err(RecvTimeoutError::Disconnected) => {
+            anyhow::bail!("Couldn't read the output of RUN `{}`", name)
+        }
+    };
+
+    let exit_code = status
+        .code()
+        .ok_or_else(|| anyhow!("RUN `{}` was terminated by a signal", name))?;
+
+    Ok(Output { stdout, exit_code })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn command(program: &str, args: &[&str], timeout_seconds: u64) -> config::Command {
+        config::Command {
+            program: program.to_owned(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout_seconds,
+            max_per_minute: 6,
+        }
+    }
+
+    #[test]
+    fn only_runs_allowed_commands() {
+        let mut commands = FxHashMap::default();
+        commands.insert("say".to_owned(), command("echo", &[], 10));
+
+        assert_eq!(allowed(&commands, "say").unwrap().program, "echo");
+        let err = allowed(&commands, "echo").unwrap_err().to_string();
+        assert!(err.starts_with("RUN `echo` isn't allowed"), "{}", err);
+    }
+
+    #[test]
+    fn limits_the_starts_per_minute() {
+        let mut starts = FxHashMap::default();
+        let start = Instant::now();
+
+        for seconds in 0..3 {
+            let now = start + Duration::from_secs(seconds);
+            rate_limit(&mut starts, "say", 3, now).unwrap();
+        }
+        let err = rate_limit(&mut starts, "say", 3, start + Duration::from_secs(59))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "RUN `say` is rate limited, it may only run 3 times a minute"
+        );
+        // Other commands have their own limit.
+        rate_limit(&mut starts, "other", 3, start + Duration::from_secs(59)).unwrap();
+        // The first start is more than a minute ago.
+        rate_limit(&mut starts, "say", 3, start + Duration::from_secs(60)).unwrap();
+    }
+
+    #[test]
+    fn passes_the_arguments_and_returns_the_output() {
+        let output = run(
+            "say",
+            &command("sh", &["-c", "echo \"$0 $1\"; exit 3"], 10),
+            &["a b".to_owned(), "c".to_owned()],
+        )
+        .unwrap();
+
+        assert_eq!(output.stdout, "a b c\n");
+        assert_eq!(output.exit_code, 3);
+    }
+
+    #[test]
+    fn kills_commands_that_take_too_long() {
+        let start = Instant::now();
+        let err = run("wait", &command("sleep", &["10"], 1), &[])
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(err, "RUN `wait` was killed after 1 seconds");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stops_waiting_for_output_kept_open_in_the_background() {
+        let start = Instant::now();
+        let err = run(
+            "detach",
+            &command("sh", &["-c", "sleep 10 & echo started"], 1),
+            &[],
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert_eq!(
+            err,
+            "RUN `detach` exited, but its output was still open after 1 seconds"
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reports_commands_killed_by_a_signal() {
+        let err = run("crash", &command("sh", &["-c", "kill -9 $$"], 10), &[])
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(err, "RUN `crash` was terminated by a signal");
+    }
+}
@@ -0,0 +1,210 @@
+use directories_next::ProjectDirs;
+use reqwest::Url;
+use rustc_hash::FxHashMap;
+use std::{fs, io, path::PathBuf};
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load();
+}
+
+/// Local settings of the user, read from `config.json` in the config directory, e.g.
+/// `~/.config/timetrackrs/config.json` on Linux. They can't be changed by the rules, which
+/// come from the server, so everything a rule may do on the computer is allowed here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The commands `RUN` may start, by the name the rules use.
+    pub commands: FxHashMap<String, Command>,
+    pub http_post: HttpPost,
+    pub triggers: Triggers,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Command {
+    pub program: String,
+    /// Passed before the arguments of the rule.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The command is killed if it takes longer.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// How often the command may be run within a minute, by all rules together.
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpPost {
+    /// The URLs `HTTP_POST` may send to, including every URL below them, e.g.
+    /// `https://chat.example.com/hooks/` allows `https://chat.example.com/hooks/abc`.
+    pub allowed_urls: Vec<String>,
+    pub timeout_seconds: u64,
+    /// How often a failed request is repeated.
+    pub retries: u32,
+}
+
+impl Default for HttpPost {
+    fn default() -> Self {
+        Self {
+            allowed_urls: vec![],
+            timeout_seconds: 5,
+            retries: 2,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Triggers {
+    /// How long the user has to leave the mouse and the keyboard alone for `IDLE_START`.
+    pub idle_after_seconds: u64,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self {
+            idle_after_seconds: 5 * 60,
+        }
+    }
+}
+
+impl HttpPost {
+    /// Whether `url` is one of the allowed URLs or below one of them. The URLs are compared
+    /// by their parts and the paths by their segments, so that e.g.
+    /// `https://example.com.evil.org` isn't below `https://example.com` and `/hooks-admin`
+    /// isn't below `/hooks`.
+    pub fn allows(&self, url: &Url) -> bool {
+        self.allowed_urls.iter().any(|allowed| {
+            let allowed = match Url::parse(allowed) {
+                Ok(allowed) => allowed,
+                Err(err) => {
+                    error!("The allowed URL `{}` is invalid: {}", allowed, err);
+                    return false;
+                }
+            };
+
+            url.scheme() == allowed.scheme()
+                && url.host_str() == allowed.host_str()
+                && url.port_or_known_default() == allowed.port_or_known_default()
+                && url.username() == allowed.username()
+                && is_below(url.path(), allowed.path())
+        })
+    }
+}
+
+/// Whether `path` is `parent` or one of the paths below it.
+fn is_below(path: &str, parent: &str) -> bool {
+    match path.strip_prefix(parent) {
+        Some(rest) => parent.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_max_per_minute() -> usize {
+    6
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "timetrackrs").map(|dirs| dirs.config_dir().join("config.json"))
+    }
+
+    /// A missing config allows nothing, a broken one is reported and ignored.
+    fn load() -> Self {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        let config = match fs::read_to_string(&path) {
+            Ok(config) => config,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                error!("Couldn't read {}: {}", path.display(), err);
+                return Self::default();
+            }
+        };
+
+        serde_json::from_str(&config).unwrap_or_else(|err| {
+            error!("Couldn't parse {}: {}", path.display(), err);
+            Self::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_post(allowed_urls: &[&str]) -> HttpPost {
+        HttpPost {
+            allowed_urls: allowed_urls.iter().map(|url| url.to_string()).collect(),
+            ..HttpPost::default()
+        }
+    }
+
+    fn allows(http_post: &HttpPost, url: &str) -> bool {
+        http_post.allows(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn allows_urls_below_an_allowed_path() {
+        let http_post = http_post(&["https://chat.example.com/hooks", "http://localhost:8080/"]);
+
+        for url in [
+            "https://chat.example.com/hooks",
+            "https://chat.example.com/hooks/",
+            "https://chat.example.com/hooks/abc?token=1",
+            "https://chat.example.com:443/hooks/abc",
+            "http://localhost:8080/anything",
+        ] {
+            assert!(allows(&http_post, url), "{}", url);
+        }
+
+        for url in [
+            "https://chat.example.com/hooks-admin",
+            "https://chat.example.com/hooksabc",
+            "https://chat.example.com/hooks/../admin",
+            "https://chat.example.com/",
+            "http://chat.example.com/hooks/abc",
+            "https://chat.example.com.evil.org/hooks/abc",
+            "https://user@chat.example.com/hooks/abc",
+            "https://chat.example.com:8443/hooks/abc",
+            "http://localhost:8081/anything",
+        ] {
+            assert!(!allows(&http_post, url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn allowed_paths_ending_in_a_slash_only_allow_what_is_below() {
+        let http_post = http_post(&["https://chat.example.com/hooks/"]);
+
+        assert!(allows(&http_post, "https://chat.example.com/hooks/abc"));
+        assert!(!allows(&http_post, "https://chat.example.com/hooks"));
+        assert!(!allows(&http_post, "https://chat.example.com/admin"));
+    }
+
+    #[test]
+    fn reads_the_idle_time_of_triggers() {
+        let config: Config =
+            serde_json::from_str(r#"{ "triggers": { "idle_after_seconds": 600 } }"#).unwrap();
+        assert_eq!(config.triggers.idle_after_seconds, 600);
+
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.triggers.idle_after_seconds, 300);
+    }
+
+    #[test]
+    fn invalid_allowed_urls_allow_nothing() {
+        let http_post = http_post(&["not a url"]);
+
+        assert!(!allows(&http_post, "https://chat.example.com/hooks"));
+    }
+}
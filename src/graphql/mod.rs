@@ -40,7 +40,8 @@ type Bigint = i64;
 type Float8 = f32;
 type Timestamptz = String;
 type Bytea = Vec<u8>;
-type Jsonb = Vec<u8>;
+/// `tags` and `meta` of `user_events`, see `docs/migrations/user_events_tags_meta_project`.
+type Jsonb = serde_json::Value;
 
 mod project_rules;
 mod user_events;
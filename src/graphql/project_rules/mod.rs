@@ -1,4 +1,5 @@
 use super::*;
+use rustc_hash::FxHashMap;
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -9,13 +10,33 @@ use super::*;
 )]
 struct UserProjectRulesQuery;
 
-/// Gets the User's projects and the rules associated with those projects.
-pub fn get_user_rules() -> anyhow::Result<Vec<(String, String)>> {
+/// A project of the user, rules can assign events to it with `ASSIGN_PROJECT`.
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub struct UserRules {
+    /// The id of the project rule and the body of its rule.
+    pub rules: Vec<(String, String)>,
+    pub projects: Vec<Project>,
+    /// The bodies of the user's library rules that can be imported with `IMPORT`, by name.
+    pub libraries: FxHashMap<String, String>,
+}
+
+/// Gets the User's projects and the rules associated with those projects, as well as the
+/// User's own library rules the project rules can import.
+pub fn get_user_rules() -> anyhow::Result<UserRules> {
     use user_project_rules_query::ResponseData;
 
     let mut vec: Vec<(String, String)> = vec![];
+    let mut projects: Vec<Project> = vec![];
 
-    let request_body = UserProjectRulesQuery::build_query(user_project_rules_query::Variables {});
+    let request_body = UserProjectRulesQuery::build_query(user_project_rules_query::Variables {
+        user_id: USER_ID.clone(),
+    });
 
     debug!("Getting User Rules");
 
@@ -25,20 +46,79 @@ pub fn get_user_rules() -> anyhow::Result<Vec<(String, String)>> {
         .send()?
         .json()?;
 
-    let user_projects = match response_data.data {
-        Some(data) => data.user_project,
+    let (user_projects, libraries) = match response_data.data {
+        Some(data) => (data.user_project, data.libraries),
         None => anyhow::bail!("Query Failed with Errors: {:?}", response_data.errors),
     };
 
     for project in user_projects.into_iter() {
-        for project_rule in project.project.project_rules {
+        let project = project.project;
+        if !projects.iter().any(|known| known.id == project.id) {
+            projects.push(Project {
+                id: project.id,
+                name: project.name,
+            });
+        }
+
+        for project_rule in project.project_rules {
             let project_id = project_rule.id;
             let rule_body = project_rule.rule.body;
             vec.push((project_id, rule_body));
         }
     }
 
+    let libraries = libraries
+        .into_iter()
+        .filter_map(|library| Some((library.name?, library.body)));
+    let libraries = unique_libraries(libraries);
+
     debug!("User Rules Retrieved");
 
-    Ok(vec)
+    Ok(UserRules {
+        rules: vec,
+        projects,
+        libraries,
+    })
+}
+
+/// Collects the libraries by name. A name that's used more than once is left out, since
+/// it's unclear which of the rules `IMPORT` should get.
+fn unique_libraries(
+    libraries: impl IntoIterator<Item = (String, String)>,
+) -> FxHashMap<String, String> {
+    let mut unique = FxHashMap::default();
+    let mut duplicates = vec![];
+    for (name, body) in libraries {
+        if unique.insert(name.clone(), body).is_some() {
+            duplicates.push(name);
+        }
+    }
+
+    for name in duplicates {
+        if unique.remove(&name).is_some() {
+            error!(
+                "There's more than one library rule named `{}`, it can't be imported",
+                name
+            );
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_libraries_with_the_same_name() {
+        let libraries = unique_libraries(vec![
+            ("common".to_owned(), "SET A = 1".to_owned()),
+            ("idle".to_owned(), "SET B = 1".to_owned()),
+            ("common".to_owned(), "SET A = 2".to_owned()),
+            ("common".to_owned(), "SET A = 3".to_owned()),
+        ]);
+
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries["idle"], "SET B = 1");
+    }
 }
@@ -37,10 +37,13 @@ impl From<Event> for Variables {
         let Event {
             windows,
             rule,
+            project,
             network,
             keyboard,
             mouse,
             seconds_since_last_input,
+            tags,
+            meta,
             ..
         } = event;
 
@@ -109,10 +112,13 @@ impl From<Event> for Variables {
             user_event_files: None,
             user_id: Some(USER_ID.clone()),
             project_rule_id: rule.map(|r| r.id),
+            project_id: project,
             keyboard: Some(keyboard as i64),
             mouse: Some(mouse as i64),
             seconds_since_last_input: Some(seconds_since_last_input as i64),
             ssid_id: network,
+            tags: Some(tags.into()),
+            meta: Some(meta.into()),
             user_event_windows: Some(user_event_window_arr_rel_insert_input),
         };
 
@@ -121,3 +127,52 @@ impl From<Event> for Variables {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Map};
+
+    fn event(tags: Vec<String>, meta: Map<String, serde_json::Value>) -> Event {
+        Event {
+            windows: vec![],
+            screenshots: None,
+            rule: None,
+            project: None,
+            network: None,
+            keyboard: 0,
+            mouse: 0,
+            seconds_since_last_input: 0,
+            tags,
+            meta,
+        }
+    }
+
+    /// The variables of the mutation as they're sent, after the event went through JSON.
+    fn sent(event: &Event) -> serde_json::Value {
+        let event: Event = serde_json::from_str(&serde_json::to_string(event).unwrap()).unwrap();
+        serde_json::to_value(Variables::from(event)).unwrap()
+    }
+
+    #[test]
+    fn sends_tags_and_meta_as_json() {
+        let mut meta = Map::new();
+        meta.insert("ticket".to_owned(), json!("ABC-123"));
+        meta.insert("minutes".to_owned(), json!(5));
+        let variables = sent(&event(vec!["client:acme".to_owned()], meta));
+
+        assert_eq!(variables["data"]["tags"], json!(["client:acme"]));
+        assert_eq!(
+            variables["data"]["meta"],
+            json!({ "ticket": "ABC-123", "minutes": 5 })
+        );
+    }
+
+    #[test]
+    fn sends_empty_tags_and_meta_without_tag_and_set_meta() {
+        let variables = sent(&event(vec![], Map::new()));
+
+        assert_eq!(variables["data"]["tags"], json!([]));
+        assert_eq!(variables["data"]["meta"], json!({}));
+    }
+}
@@ -13,7 +13,10 @@ extern crate anyhow;
 extern crate graphql_client;
 
 pub mod capture;
+pub mod command;
+pub mod config;
 pub mod graphql;
+pub mod notification;
 pub mod rest_api;
 pub mod scripting;
 pub mod util;
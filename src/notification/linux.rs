@@ -0,0 +1,52 @@
+use super::{Notification, Urgency};
+use dbus::{
+    arg::{PropMap, Variant},
+    blocking::Connection,
+};
+use std::{convert::TryInto, time::Duration};
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+const APP_NAME: &str = "timetrackrs";
+
+/// How long to wait for the notification server to answer.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends `notification` to the notification server on the session bus, see
+/// https://specifications.freedesktop.org/notification-spec/latest/
+pub fn notify(notification: &Notification) -> anyhow::Result<()> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(DESTINATION, PATH, CALL_TIMEOUT);
+
+    let urgency: u8 = match notification.urgency {
+        Urgency::Low => 0,
+        Urgency::Normal => 1,
+        Urgency::Critical => 2,
+    };
+    let mut hints = PropMap::new();
+    hints.insert("urgency".to_owned(), Variant(Box::new(urgency)));
+
+    // -1 leaves it to the server.
+    let timeout: i32 = match notification.timeout {
+        Some(timeout) => timeout.as_millis().try_into().unwrap_or(i32::MAX),
+        None => -1,
+    };
+
+    let (id,): (u32,) = proxy.method_call(
+        DESTINATION,
+        "Notify",
+        (
+            APP_NAME,
+            0_u32,
+            "",
+            notification.title.as_str(),
+            notification.body.as_str(),
+            Vec::<String>::new(),
+            hints,
+            timeout,
+        ),
+    )?;
+    debug!("Sent notification {}", id);
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::{fmt, time::Duration};
+
+#[cfg(target_os = "linux")]
+mod linux;
+
+/// How pressing a notification is, the desktop may e.g. keep critical ones open until
+/// they're dismissed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "LOW" => Some(Urgency::Low),
+            "NORMAL" => Some(Urgency::Normal),
+            "CRITICAL" => Some(Urgency::Critical),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Urgency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Urgency::Low => write!(f, "LOW"),
+            Urgency::Normal => write!(f, "NORMAL"),
+            Urgency::Critical => write!(f, "CRITICAL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+    pub urgency: Urgency,
+    /// When the notification closes by itself, the desktop decides if it's `None`.
+    pub timeout: Option<Duration>,
+}
+
+/// Shows `notification` on the desktop of the user.
+#[cfg(target_os = "linux")]
+pub fn notify(notification: &Notification) -> anyhow::Result<()> {
+    linux::notify(notification)
+}
+
+/// Desktop notifications are only supported on Linux so far, elsewhere they're logged.
+#[cfg(not(target_os = "linux"))]
+pub fn notify(notification: &Notification) -> anyhow::Result<()> {
+    info!(
+        "Notification ({}): {}: {}",
+        notification.urgency, notification.title, notification.body
+    );
+    Ok(())
+}
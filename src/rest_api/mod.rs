@@ -40,7 +40,9 @@ struct Variables<'a> {
 mod event;
 mod rule;
 mod screenshot;
+mod webhook;
 
 pub use event::*;
 pub use rule::*;
 pub use screenshot::*;
+pub use webhook::*;
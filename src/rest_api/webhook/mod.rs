@@ -0,0 +1,54 @@
+use crate::config::{Config, CONFIG};
+use reqwest::{blocking, Url};
+use serde_json::Value;
+use std::{thread, time::Duration};
+
+lazy_static! {
+    // Not `HTTP_CLIENT`, its default headers carry the credentials of the API, which must
+    // never be sent to the endpoints of the rules.
+    static ref WEBHOOK_CLIENT: blocking::Client = blocking::Client::builder()
+        .timeout(Duration::from_secs(CONFIG.http_post.timeout_seconds))
+        .build()
+        .unwrap();
+}
+
+/// Posts `body` as JSON to `url`, which has to be allowed in the config of the user. A
+/// failed request is retried as long as it's worth it, i.e. not after a `4xx` response.
+pub fn post_json(url: &str, body: &Value) -> anyhow::Result<()> {
+    let url = Url::parse(url).map_err(|err| anyhow!("`{}` is not a valid URL: {}", url, err))?;
+    if !CONFIG.http_post.allows(&url) {
+        anyhow::bail!(
+            "HTTP_POST to `{}` isn't allowed, the allowed URLs are set in {}",
+            url,
+            Config::path().map_or_else(
+                || "config.json".to_owned(),
+                |path| path.display().to_string()
+            )
+        );
+    }
+
+    let retries = CONFIG.http_post.retries;
+    let mut attempt = 0;
+    loop {
+        let error = match WEBHOOK_CLIENT.post(url.clone()).json(body).send() {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status().is_client_error() => {
+                anyhow::bail!("HTTP_POST to `{}` failed with {}", url, response.status())
+            }
+            Ok(response) => format!("failed with {}", response.status()),
+            Err(err) => format!("failed: {}", err),
+        };
+
+        if attempt >= retries {
+            anyhow::bail!(
+                "HTTP_POST to `{}` {}, gave up after {} attempt(s)",
+                url,
+                error,
+                attempt + 1
+            );
+        }
+        attempt += 1;
+        debug!("HTTP_POST to `{}` {}, retrying", url, error);
+        thread::sleep(Duration::from_millis(500) * attempt);
+    }
+}
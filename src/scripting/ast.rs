@@ -0,0 +1,288 @@
+use super::{Function, Schedule, Trigger};
+use crate::notification::Urgency;
+use regex::Regex;
+use rustc_hash::FxHashMap;
+use std::{fmt, sync::Arc, time::Duration};
+
+/// A parsed rule body.
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// When the body gets executed, set with `EVERY`, `AT` and `CRON`. Only empty if there
+    /// are `handlers`.
+    pub schedules: Vec<Schedule>,
+    /// Everything declared with `DEFINE`, by name.
+    pub procedures: FxHashMap<String, Arc<Procedure>>,
+    /// The `ON` blocks in the order they appear in.
+    pub handlers: Vec<Handler>,
+    pub body: Vec<Stmt>,
+}
+
+/// `ON WINDOW_CHANGE`, a block that runs whenever the trigger fires.
+#[derive(Debug, Clone)]
+pub struct Handler {
+    pub trigger: Trigger,
+    pub body: Vec<Stmt>,
+}
+
+/// `DEFINE NAME(PARAM, ...)`, a block that can be run with `CALL`.
+#[derive(Debug, Clone)]
+pub struct Procedure {
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    /// `IF`, any number of `ELSEIF` and an optional `ELSE`, closed by a single `END`.
+    If {
+        branches: Vec<Branch>,
+        otherwise: Option<Vec<Stmt>>,
+    },
+    /// `ITERATE [W IN] WINDOWS [WHERE <condition>]`, runs the body once for every selected
+    /// element.
+    Iterate {
+        selection: Selection,
+        body: Vec<Stmt>,
+    },
+    /// Stops the innermost `ITERATE`.
+    Break,
+    /// Goes on with the next element of the innermost `ITERATE`.
+    Continue,
+    /// `SET NAME = <expr>`
+    Set {
+        name: String,
+        value: Expr,
+    },
+    /// `CALL NAME(<expr>, ...)`, the procedure is known to exist and to take as many
+    /// arguments.
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Print(Expr),
+    /// `LOG <level> <expr>`, logged with the id of the rule as the target.
+    Log {
+        level: log::Level,
+        message: Expr,
+    },
+    /// `TAG "category" "value"`, added to the event of the next `SAVE_TO_DB`.
+    Tag {
+        category: Expr,
+        value: Expr,
+    },
+    /// `SET_META key <expr>`, stored with the event of the next `SAVE_TO_DB`.
+    SetMeta {
+        key: String,
+        value: Expr,
+    },
+    /// `ASSIGN_PROJECT "name"`, the next `SAVE_TO_DB` saves the event for the project with
+    /// the name or the id instead of the project of the rule.
+    AssignProject(Expr),
+    /// `NOTIFY "title" "body" [URGENCY LOW|NORMAL|CRITICAL] [TIMEOUT <duration>]`
+    Notify {
+        title: Expr,
+        body: Expr,
+        urgency: Urgency,
+        timeout: Option<Duration>,
+    },
+    /// `RUN "name" [ARGS [<expr>, ...]]`, runs a command of the allow-list in the config of
+    /// the user and sets `RUN_STDOUT` and `RUN_EXIT_CODE`.
+    Run {
+        name: String,
+        args: Vec<Expr>,
+    },
+    /// `HTTP_POST <url> { "key": <expr>, ... }`, the URL has to be allowed in the config
+    /// of the user.
+    HttpPost {
+        url: Expr,
+        body: Json,
+    },
+    /// `PERSIST NAME = <default> [RESET DAILY|WEEKLY]`, only at the top level of a rule.
+    /// The variable keeps its value across restarts, until it's reset to `default`.
+    Persist {
+        name: String,
+        default: Expr,
+        reset: Option<Reset>,
+    },
+    SaveToDb,
+    GetNetworkSsid,
+    GetPeripherals,
+    GetWindows,
+    CaptureScreen(Screens),
+}
+
+/// When a `PERSIST`ed variable goes back to its default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reset {
+    Daily,
+    Weekly,
+}
+
+/// A JSON document whose values are expressions, e.g. the body of `HTTP_POST`.
+#[derive(Debug, Clone)]
+pub enum Json {
+    Object(Vec<(String, Json)>),
+    Array(Vec<Json>),
+    Value(Expr),
+}
+
+/// `[W IN] WINDOWS [WHERE <condition>]`, the elements of a list the condition holds for.
+/// While an element is looked at, its fields are set as variables, or as fields of the
+/// `binding` if there's one.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub binding: Option<String>,
+    pub key: String,
+    pub filter: Option<Expr>,
+}
+
+/// The functions that sum up a `Selection`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregate {
+    /// `COUNT(WINDOWS WHERE ...)`, how many elements are selected.
+    Count,
+    /// `ANY(WINDOWS WHERE ...)`, whether at least one element is selected.
+    Any,
+    /// `ALL(WINDOWS WHERE ...)`, whether every element is selected.
+    All,
+    /// `FIRST(WINDOWS WHERE ...)`, the first selected element, a window without any fields
+    /// if there's none.
+    First,
+    /// `SUM(WINDOWS.MEMORY WHERE ...)`, the sum of a field of the selected elements.
+    Sum(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub condition: Expr,
+    /// Set by `FOR`, the branch is only taken once the condition held long enough.
+    pub sustained: Option<Sustained>,
+    pub body: Vec<Stmt>,
+}
+
+/// `IF <condition> FOR 5 MINUTES`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sustained {
+    /// Identifies the condition, the interpreter keeps track of since when it holds by it.
+    pub id: usize,
+    pub duration: chrono::Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screens {
+    Primary,
+    All,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    /// A quoted value such as `"Brave"`.
+    Str(String),
+    Int(i64),
+    Float(f32),
+    /// A number followed by a time unit, e.g. `2 HOURS`.
+    Duration(chrono::Duration),
+    /// A quoted value with `{VARIABLE}` placeholders, only used by `PRINT` and `LOG`.
+    Format(Vec<Segment>),
+    /// A reference into the variable map such as `TITLE`.
+    Variable(String),
+    /// `W.TITLE`, a field of a window.
+    Field {
+        name: String,
+        field: String,
+    },
+    Aggregate {
+        function: Aggregate,
+        selection: Box<Selection>,
+    },
+    /// `LOWER(TITLE)`
+    Function {
+        function: Function,
+        args: Vec<Expr>,
+    },
+    /// `[a, "b"]`, only valid on the right hand side of `IN`.
+    Array(Vec<Expr>),
+    Not(Box<Expr>),
+    /// Only evaluates the right hand side if the left hand side holds.
+    And(Box<Expr>, Box<Expr>),
+    /// Only evaluates the right hand side if the left hand side doesn't hold.
+    Or(Box<Expr>, Box<Expr>),
+    Compare {
+        op: CompareOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `MATCH "regex"` or `MATCH IN ["regex", ...]`, the regexes are compiled while parsing.
+    Match {
+        value: Box<Expr>,
+        regexes: Vec<Regex>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Literal(String),
+    /// `{NAME}` or `{NAME:spec}`
+    Variable {
+        name: String,
+        spec: FormatSpec,
+    },
+}
+
+/// The part after the colon in `{MEMORY:>10}`, a subset of what Rust's `format!` accepts:
+/// `[[fill]align][0][width][.precision][pattern]`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FormatSpec {
+    pub fill: Option<char>,
+    pub align: Option<Align>,
+    /// Pads numbers with zeros after the sign, like `{:08}`.
+    pub zero: bool,
+    pub width: Option<usize>,
+    /// The number of decimals of a decimal number, or the maximum length of anything else.
+    pub precision: Option<usize>,
+    /// How a date is written, in the `strftime` syntax, e.g. `%H:%M` in `{NOW:%H:%M}`.
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Bigger,
+    Lesser,
+    In,
+}
+
+/// Arithmetic operators, `+` concatenates if either side is a string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        };
+        write!(f, "{}", op)
+    }
+}
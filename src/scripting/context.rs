@@ -0,0 +1,75 @@
+use super::{state::State, Rule, VariableMapType};
+use crate::{capture::SharedCapturer, graphql::Project};
+use chrono::{DateTime, Local};
+use rustc_hash::FxHashMap;
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+
+/// The statements that run, the program itself or one of its `ON` blocks by index. Each of
+/// them counts its runs on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Body {
+    Program,
+    Handler(usize),
+}
+
+/// Everything a rule keeps between its executions: its variables, what the next
+/// `SAVE_TO_DB` sends and the capturers it uses. The `Interpreter` only holds the
+/// program, so whoever owns the context decides where and when the rule runs.
+pub struct ExecutionContext {
+    pub rule: Rule,
+    /// The built-in variables and the ones created with `SET` outside of procedures.
+    pub variables: VariableMapType,
+    /// The projects of the user, `ASSIGN_PROJECT` picks one of them. Unlike `PROJECTS`
+    /// rules can't change them.
+    pub projects: Vec<Project>,
+    /// How often the program and each of its `ON` blocks have run, including the current
+    /// run.
+    pub(super) runs: FxHashMap<Body, u64>,
+    /// For every `IF ... FOR` condition by the body it ran in and its id: the last run of
+    /// the body in which it held and since when it has held without interruption.
+    pub(super) sustained: FxHashMap<(Body, usize), (u64, DateTime<Local>)>,
+    /// Collected by `TAG` and `SET_META` until the next `SAVE_TO_DB` sends them.
+    pub(super) tags: Vec<String>,
+    pub(super) meta: Map<String, Value>,
+    /// The id of the project set by `ASSIGN_PROJECT` for the next `SAVE_TO_DB`.
+    pub(super) project: Option<String>,
+    /// The `PERSIST`ed variables.
+    pub(super) state: State,
+    /// What `GET_WINDOWS` reads, shared with the other rules.
+    pub(super) capturer: Arc<Mutex<SharedCapturer>>,
+    /// Created by the first `CAPTURE_SCREEN`.
+    pub(super) screen_capturer: Option<Box<dyn captis::Capturer>>,
+}
+
+/// The project with the id or the name `project`, names are compared regardless of the case.
+pub(super) fn find_project<'a>(projects: &'a [Project], project: &str) -> Option<&'a Project> {
+    projects
+        .iter()
+        .find(|known| known.id == project || known.name.to_lowercase() == project.to_lowercase())
+}
+
+impl ExecutionContext {
+    /// Sets `RULE_ID` and `RULE_BODY`, the other built-in variables are up to the caller.
+    /// Rules that run together should share `capturer`, so that the windows are only
+    /// captured once per tick.
+    pub fn new(rule: Rule, capturer: Arc<Mutex<SharedCapturer>>) -> Self {
+        let mut variables = VariableMapType::default();
+        variables.insert("RULE_ID".into(), rule.id.clone().into());
+        variables.insert("RULE_BODY".into(), rule.body.clone().into());
+
+        Self {
+            rule,
+            variables,
+            projects: vec![],
+            runs: FxHashMap::default(),
+            sustained: FxHashMap::default(),
+            tags: vec![],
+            meta: Map::new(),
+            project: None,
+            state: State::default(),
+            capturer,
+            screen_capturer: None,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+/// Where in a rule body something is, lines and columns are 1-based and counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Stable identifiers for every problem the parser reports. The numbers are part of the
+/// output rule authors see, so existing variants must never be renumbered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnexpectedCharacter = 1,
+    UnterminatedString = 2,
+    UnexpectedToken = 3,
+    UnknownStatement = 4,
+    MissingEnd = 5,
+    UnmatchedEnd = 6,
+    MissingEvery = 7,
+    /// No longer reported, rules can have several schedules.
+    DuplicateEvery = 8,
+    InvalidEvery = 9,
+    InvalidRegex = 10,
+    InvalidScreen = 11,
+    ExpectedCondition = 12,
+    InvalidNumber = 13,
+    UnknownProcedure = 14,
+    DuplicateProcedure = 15,
+    ArgumentCount = 16,
+    UnknownLibrary = 17,
+    ImportCycle = 18,
+    InvalidLibrary = 19,
+    UnterminatedComment = 20,
+    InvalidFormat = 21,
+    InvalidLogLevel = 22,
+    TypeMismatch = 23,
+    InvalidSchedule = 24,
+    UnknownTrigger = 25,
+    UnknownField = 26,
+    UnknownFunction = 27,
+    UnknownProject = 28,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{:04}", *self as u16)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Span,
+    /// An optional hint on how to fix the problem.
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            message: message.into(),
+            span,
+            help: None,
+        }
+    }
+
+    pub fn warning(code: ErrorCode, span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(code, span, message)
+        }
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Renders the diagnostic together with the offending line of `source`, similar to how
+    /// rustc does it. `origin` names the rule, e.g. its id.
+    pub fn render(&self, source: &str, origin: &str) -> String {
+        let Span { line, column, len } = self.span;
+
+        // Tabs are replaced, otherwise the carets wouldn't line up with the code.
+        let code = source
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or_default()
+            .replace('\t', " ");
+
+        let gutter = " ".repeat(line.to_string().len());
+
+        let mut rendered = format!(
+            "{}[{}]: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.severity,
+            self.code,
+            self.message,
+            gutter,
+            origin,
+            line,
+            column,
+            gutter,
+            line,
+            code,
+            gutter,
+            " ".repeat(column.saturating_sub(1)),
+            "^".repeat(len.max(1)),
+        );
+
+        if let Some(help) = &self.help {
+            rendered.push_str(&format!("\n{} = help: {}", gutter, help));
+        }
+
+        rendered
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}]: {} (line {}, column {})",
+            self.severity, self.code, self.message, self.span.line, self.span.column
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_warnings_with_tabs() {
+        let diagnostic = Diagnostic::warning(
+            ErrorCode::DuplicateEvery,
+            Span {
+                line: 2,
+                column: 2,
+                len: 5,
+            },
+            "Something",
+        );
+        assert_eq!(
+            diagnostic.render("EVERY 1 SECONDS\n\tEVERY 2 SECONDS", "rule"),
+            "warning[E0008]: Something\n --> rule:2:2\n  |\n2 |  EVERY 2 SECONDS\n  |  ^^^^^"
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "warning[E0008]: Something (line 2, column 2)"
+        );
+    }
+
+    #[test]
+    fn renders_empty_spans_with_one_caret() {
+        let diagnostic = Diagnostic::error(
+            ErrorCode::UnexpectedToken,
+            Span {
+                line: 1,
+                column: 3,
+                len: 0,
+            },
+            "Expected a value, found end of rule",
+        )
+        .with_help("add one");
+        assert_eq!(
+            diagnostic.render("AT", "rule"),
+            "error[E0003]: Expected a value, found end of rule\n --> rule:1:3\n  |\n1 | AT\n  |   ^\n  = help: add one"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use super::{Type, Variable};
+use reqwest::Url;
+use std::path::Path;
+
+/// The built-in functions, e.g. `LOWER(TITLE)`. They can be used wherever a value can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Lower,
+    Upper,
+    Trim,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Replace,
+    Split,
+    /// `SUBSTRING(string, start, length)`, counted in characters.
+    Substring,
+    /// The number of characters.
+    Len,
+    Basename,
+    Dirname,
+    /// The host of a URL, the scheme may be left out, e.g. `github.com/rust-lang`.
+    UrlHost,
+}
+
+impl Function {
+    pub const ALL: [Function; 13] = [
+        Function::Lower,
+        Function::Upper,
+        Function::Trim,
+        Function::Contains,
+        Function::StartsWith,
+        Function::EndsWith,
+        Function::Replace,
+        Function::Split,
+        Function::Substring,
+        Function::Len,
+        Function::Basename,
+        Function::Dirname,
+        Function::UrlHost,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Lower => "LOWER",
+            Function::Upper => "UPPER",
+            Function::Trim => "TRIM",
+            Function::Contains => "CONTAINS",
+            Function::StartsWith => "STARTS_WITH",
+            Function::EndsWith => "ENDS_WITH",
+            Function::Replace => "REPLACE",
+            Function::Split => "SPLIT",
+            Function::Substring => "SUBSTRING",
+            Function::Len => "LEN",
+            Function::Basename => "BASENAME",
+            Function::Dirname => "DIRNAME",
+            Function::UrlHost => "URL_HOST",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|function| function.name() == name)
+    }
+
+    /// The types of the parameters.
+    pub fn params(self) -> &'static [Type] {
+        match self {
+            Function::Lower
+            | Function::Upper
+            | Function::Trim
+            | Function::Len
+            | Function::Basename
+            | Function::Dirname
+            | Function::UrlHost => &[Type::Str],
+            Function::Contains | Function::StartsWith | Function::EndsWith | Function::Split => {
+                &[Type::Str, Type::Str]
+            }
+            Function::Replace => &[Type::Str, Type::Str, Type::Str],
+            Function::Substring => &[Type::Str, Type::Int, Type::Int],
+        }
+    }
+
+    /// The type of the result.
+    pub fn result(self) -> Type {
+        match self {
+            Function::Contains | Function::StartsWith | Function::EndsWith => Type::Bool,
+            Function::Split => Type::List(Box::new(Type::Str)),
+            Function::Len => Type::Int,
+            _ => Type::Str,
+        }
+    }
+
+    /// Applies the function, the arguments have already been checked by the parser, but
+    /// the ones of type `Any` are only known now.
+    pub fn call(self, args: &[Variable]) -> anyhow::Result<Variable> {
+        let string = |index: usize| -> anyhow::Result<&str> {
+            args[index].as_str().ok_or_else(|| {
+                anyhow!(
+                    "Argument {} of {} has to be text, got {}",
+                    index + 1,
+                    self.name(),
+                    args[index]
+                )
+            })
+        };
+        let int = |index: usize| -> anyhow::Result<usize> {
+            match &args[index] {
+                Variable::Int(int) if *int >= 0 => Ok(*int as usize),
+                Variable::U64(int) => Ok(*int as usize),
+                variable => anyhow::bail!(
+                    "Argument {} of {} has to be a whole number of at least 0, got {}",
+                    index + 1,
+                    self.name(),
+                    variable
+                ),
+            }
+        };
+
+        let result = match self {
+            Function::Lower => string(0)?.to_lowercase().into(),
+            Function::Upper => string(0)?.to_uppercase().into(),
+            Function::Trim => string(0)?.trim().into(),
+            Function::Contains => string(0)?.contains(string(1)?).into(),
+            Function::StartsWith => string(0)?.starts_with(string(1)?).into(),
+            Function::EndsWith => string(0)?.ends_with(string(1)?).into(),
+            Function::Replace => string(0)?.replace(string(1)?, string(2)?).into(),
+            Function::Split => {
+                let separator = string(1)?;
+                if separator.is_empty() {
+                    anyhow::bail!("SPLIT needs a separator that isn't empty");
+                }
+                string(0)?
+                    .split(separator)
+                    .map(Variable::from)
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Function::Substring => string(0)?
+                .chars()
+                .skip(int(1)?)
+                .take(int(2)?)
+                .collect::<String>()
+                .into(),
+            Function::Len => string(0)?.chars().count().into(),
+            Function::Basename => Path::new(string(0)?)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+                .into(),
+            Function::Dirname => Path::new(string(0)?)
+                .parent()
+                .map(|parent| parent.to_string_lossy().into_owned())
+                .unwrap_or_default()
+                .into(),
+            Function::UrlHost => url_host(string(0)?).unwrap_or_default().into(),
+        };
+        Ok(result)
+    }
+}
+
+fn url_host(string: &str) -> Option<String> {
+    let string = string.trim();
+    let url = match Url::parse(string) {
+        Ok(url) if url.has_host() => url,
+        _ => Url::parse(&format!("https://{}", string)).ok()?,
+    };
+    url.host_str().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(function: Function, args: &[Variable]) -> Variable {
+        function.call(args).unwrap()
+    }
+
+    fn text(string: &str) -> Variable {
+        string.into()
+    }
+
+    #[test]
+    fn counts_characters_instead_of_bytes() {
+        assert_eq!(call(Function::Len, &[text("Grüße 👋")]), Variable::Int(7));
+        assert_eq!(
+            call(
+                Function::Substring,
+                &[text("Grüße 👋"), Variable::Int(2), Variable::Int(3)]
+            ),
+            text("üße")
+        );
+        assert_eq!(
+            call(
+                Function::Substring,
+                &[text("Grüße 👋"), Variable::U64(6), Variable::Int(1)]
+            ),
+            text("👋")
+        );
+    }
+
+    #[test]
+    fn substrings_end_at_the_end_of_the_text() {
+        let substring = |start, length| {
+            call(
+                Function::Substring,
+                &[text("abc"), Variable::Int(start), Variable::Int(length)],
+            )
+        };
+        assert_eq!(substring(1, 10), text("bc"));
+        assert_eq!(substring(3, 1), text(""));
+        assert_eq!(substring(10, 1), text(""));
+
+        let err = Function::Substring
+            .call(&[text("abc"), Variable::Int(-1), Variable::Int(1)])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Argument 2 of SUBSTRING has to be a whole number of at least 0, got -1"
+        );
+    }
+
+    #[test]
+    fn splits_by_a_separator_that_isnt_empty() {
+        assert_eq!(
+            call(Function::Split, &[text("a, b,, c"), text(", ")]),
+            Variable::from(vec![text("a"), text("b,"), text("c")])
+        );
+        assert_eq!(
+            call(Function::Split, &[text(""), text(",")]),
+            Variable::from(vec![text("")])
+        );
+
+        let err = Function::Split.call(&[text("abc"), text("")]).unwrap_err();
+        assert_eq!(err.to_string(), "SPLIT needs a separator that isn't empty");
+    }
+
+    #[test]
+    fn splits_paths_into_their_directory_and_name() {
+        let basename = |path| call(Function::Basename, &[text(path)]);
+        let dirname = |path| call(Function::Dirname, &[text(path)]);
+
+        assert_eq!(basename("/usr/bin/firefox"), text("firefox"));
+        assert_eq!(dirname("/usr/bin/firefox"), text("/usr/bin"));
+        // The root has neither a name nor a parent.
+        assert_eq!(basename("/"), text(""));
+        assert_eq!(dirname("/"), text(""));
+        assert_eq!(basename("notes/todo.md"), text("todo.md"));
+        assert_eq!(dirname("notes/todo.md"), text("notes"));
+        assert_eq!(basename("todo.md"), text("todo.md"));
+        assert_eq!(dirname("todo.md"), text(""));
+        assert_eq!(basename("notes/"), text("notes"));
+    }
+
+    #[test]
+    fn finds_the_host_of_urls_with_and_without_a_scheme() {
+        let host = |url| call(Function::UrlHost, &[text(url)]);
+
+        assert_eq!(
+            host("https://GitHub.com:8080/rust-lang"),
+            text("github.com")
+        );
+        assert_eq!(host("github.com/rust-lang/rust"), text("github.com"));
+        assert_eq!(host(" localhost:3000 "), text("localhost"));
+        assert_eq!(host("not a url"), text(""));
+        assert_eq!(host(""), text(""));
+    }
+
+    #[test]
+    fn reports_arguments_that_arent_text() {
+        let err = Function::Upper.call(&[Variable::Int(5)]).unwrap_err();
+        assert_eq!(err.to_string(), "Argument 1 of UPPER has to be text, got 5");
+    }
+}
@@ -1,12 +1,36 @@
+use super::{
+    context::{find_project, Body},
+    state::Persisted,
+    window_field_type, Aggregate, Align, BinaryOp, Branch, CompareOp, ExecutionContext, Expr,
+    FormatSpec, Json, Procedure, Program, Reset, Screens, Segment, Selection, Stmt, Trigger, Type,
+};
+use crate::{
+    capture::pc_common::{get_network_ssid, Event, Window, KEYSTROKES, MOUSE_CLICKS},
+    command::run_command,
+    graphql::{get_or_insert_user_ssid, send_user_event},
+    notification::{notify, Notification},
+    rest_api::{post_json, send_screenshots},
+};
+use chrono::{DateTime, Duration, Local, TimeZone, Timelike};
 use rustc_hash::FxHashMap;
 use serde_json::Value;
-use std::{cmp::Ordering, fmt, rc::Rc, sync::Arc};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    convert::TryInto,
+    fmt,
+    sync::{atomic, Arc},
+};
 
-pub type VariableMapType = FxHashMap<&'static str, Variable>;
-pub type ConditionalFn = Box<dyn FnMut() -> bool>;
+/// Keys are mostly the names of built-in variables, `Cow` spares allocating those while
+/// still allowing names that are only known after parsing a rule, e.g. from `SET`.
+pub type VariableMapType = FxHashMap<Cow<'static, str>, Variable>;
+
+/// How deep `CALL`s may be nested, a procedure that keeps calling itself is stopped here.
+const MAX_CALL_DEPTH: usize = 64;
 
 pub trait Executable {
-    fn execute(&mut self) -> anyhow::Result<()>;
+    fn execute(&mut self, context: &mut ExecutionContext) -> anyhow::Result<()>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,11 +41,12 @@ pub struct Rule {
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Variable {
-    Int(usize),
+    Int(i64),
     U64(u64),
     Float(f32),
     Bool(bool),
-    RcStr(Rc<String>),
+    DateTime(DateTime<Local>),
+    Duration(Duration),
     ArcStr(Arc<String>),
     Vector(Box<Vec<Variable>>),
     Map(Box<VariableMapType>),
@@ -36,9 +61,10 @@ impl std::fmt::Display for Variable {
             Int(int) => write!(f, "{}", int),
             U64(int) => write!(f, "{}", int),
             Float(float) => write!(f, "{}", float),
-            RcStr(string) => write!(f, "{}", *string),
             ArcStr(string) => write!(f, "{}", *string),
             Bool(boolean) => write!(f, "{}", boolean),
+            DateTime(date_time) => write!(f, "{}", date_time.format("%Y-%m-%d %H:%M:%S")),
+            Duration(duration) => write_duration(f, *duration),
             Vector(vec) => write!(f, "{:?}", vec),
             Map(map) => write!(f, "{:?}", map),
             SerdeJsonVector(value) => write!(f, "{:?}", value),
@@ -49,13 +75,13 @@ impl std::fmt::Display for Variable {
 
 impl From<&str> for Variable {
     fn from(string: &str) -> Self {
-        Self::RcStr(Rc::new(string.into()))
+        Self::ArcStr(Arc::new(string.into()))
     }
 }
 
 impl From<String> for Variable {
     fn from(string: String) -> Self {
-        Self::RcStr(Rc::new(string))
+        Self::ArcStr(Arc::new(string))
     }
 }
 
@@ -67,6 +93,12 @@ impl From<Arc<String>> for Variable {
 
 impl From<usize> for Variable {
     fn from(number: usize) -> Self {
+        Self::Int(number as i64)
+    }
+}
+
+impl From<i64> for Variable {
+    fn from(number: i64) -> Self {
         Self::Int(number)
     }
 }
@@ -89,12 +121,47 @@ impl From<bool> for Variable {
     }
 }
 
+impl From<DateTime<Local>> for Variable {
+    fn from(date_time: DateTime<Local>) -> Self {
+        Self::DateTime(date_time)
+    }
+}
+
+impl From<Duration> for Variable {
+    fn from(duration: Duration) -> Self {
+        Self::Duration(duration)
+    }
+}
+
 impl From<serde_json::Value> for Variable {
     fn from(value: serde_json::Value) -> Self {
         Self::SerdeJson(Box::new(value))
     }
 }
 
+impl From<&Variable> for Value {
+    fn from(variable: &Variable) -> Self {
+        use Variable::*;
+        match variable {
+            Int(int) => (*int).into(),
+            U64(int) => (*int).into(),
+            Float(float) => (*float).into(),
+            Bool(boolean) => (*boolean).into(),
+            DateTime(date_time) => date_time.to_rfc3339().into(),
+            // In seconds, like `SECONDS_SINCE_LAST_INPUT`.
+            Duration(duration) => (duration.num_milliseconds() as f64 / 1000.0).into(),
+            ArcStr(string) => string.as_str().into(),
+            Vector(vec) => vec.iter().map(Value::from).collect(),
+            Map(map) => map
+                .iter()
+                .map(|(key, variable)| (key.to_string(), Value::from(variable)))
+                .collect(),
+            SerdeJsonVector(vec) => (**vec).clone().into(),
+            SerdeJson(value) => (**value).clone(),
+        }
+    }
+}
+
 impl From<VariableMapType> for Variable {
     fn from(variables_map: VariableMapType) -> Self {
         Self::Map(Box::new(variables_map))
@@ -114,7 +181,12 @@ impl PartialOrd<Variable> for Variable {
             (Int(i), Int(j)) => i.partial_cmp(j),
             (U64(i), U64(j)) => i.partial_cmp(j),
             (Float(i), Float(j)) => i.partial_cmp(j),
-            _ => None,
+            (Int(i), U64(j)) => (*i as i128).partial_cmp(&(*j as i128)),
+            (U64(i), Int(j)) => (*i as i128).partial_cmp(&(*j as i128)),
+            (DateTime(i), DateTime(j)) => i.partial_cmp(j),
+            (Duration(i), Duration(j)) => i.partial_cmp(j),
+            (DateTime(_) | Duration(_), _) | (_, DateTime(_) | Duration(_)) => None,
+            _ => self.as_f64()?.partial_cmp(&other.as_f64()?),
         }
     }
 }
@@ -123,10 +195,9 @@ impl PartialEq<String> for Variable {
     fn eq(&self, other: &String) -> bool {
         use Variable::*;
         match self {
-            RcStr(string) => **string == *other,
             ArcStr(string) => **string == *other,
             Int(i) => {
-                let num: usize = match other.parse() {
+                let num: i64 = match other.parse() {
                     Ok(num) => num,
                     Err(_) => return false,
                 };
@@ -161,137 +232,1728 @@ impl PartialEq<String> for Variable {
     }
 }
 
-pub struct Instruction(Box<dyn FnMut() -> anyhow::Result<()>>);
+impl Variable {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Variable::ArcStr(string) => Some(string.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Equality as used by `EQ` and `IN`, a quoted value equals a number or a boolean
+    /// if it's the textual representation of it.
+    pub fn loosely_eq(&self, other: &Variable) -> bool {
+        match (self.as_str(), other.as_str()) {
+            (Some(first), Some(second)) => first == second,
+            (Some(string), None) => *other == string.to_owned(),
+            (None, Some(string)) => *self == string.to_owned(),
+            (None, None) => self == other,
+        }
+    }
 
-impl<F: FnMut() -> anyhow::Result<()> + 'static> From<F> for Instruction {
-    fn from(function: F) -> Self {
-        Self(Box::new(function))
+    /// Ordering as used by `BIGGER` and `LESSER`, a quoted value gets parsed into the
+    /// number type of the other side.
+    pub fn loosely_cmp(&self, other: &Variable) -> Option<Ordering> {
+        match (self.as_str(), other.as_str()) {
+            (Some(first), Some(second)) => first
+                .parse::<i64>()
+                .ok()?
+                .partial_cmp(&second.parse::<i64>().ok()?),
+            (Some(string), None) => Self::parse_like(string, other)?.partial_cmp(other),
+            (None, Some(string)) => self.partial_cmp(&Self::parse_like(string, self)?),
+            (None, None) => self.partial_cmp(other),
+        }
+    }
+
+    /// Any number widened to `f64`, so that numbers of different types can be compared.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Variable::Int(int) => Some(*int as f64),
+            Variable::U64(int) => Some(*int as f64),
+            Variable::Float(float) => Some(*float as f64),
+            _ => None,
+        }
+    }
+
+    /// Applies an arithmetic operator. Integers stay integers, so `/` rounds towards zero,
+    /// and `+` concatenates as soon as one side is a string.
+    pub fn arithmetic(&self, op: BinaryOp, other: &Variable) -> anyhow::Result<Variable> {
+        use Variable::*;
+
+        if op == BinaryOp::Add && (self.as_str().is_some() || other.as_str().is_some()) {
+            return Ok(format!("{}{}", self, other).into());
+        }
+
+        if let Some(result) = self.temporal_arithmetic(op, other) {
+            return result.ok_or_else(|| {
+                anyhow!(
+                    "Can't calculate {} {} {}, the result is out of range or the operator doesn't apply to dates and durations",
+                    self,
+                    op,
+                    other
+                )
+            });
+        }
+
+        let integer = |variable: &Variable| match variable {
+            Int(int) => Some(*int as i128),
+            U64(int) => Some(*int as i128),
+            _ => None,
+        };
+
+        if let (Some(lhs), Some(rhs)) = (integer(self), integer(other)) {
+            let result = match op {
+                BinaryOp::Add => lhs.checked_add(rhs),
+                BinaryOp::Sub => lhs.checked_sub(rhs),
+                BinaryOp::Mul => lhs.checked_mul(rhs),
+                BinaryOp::Div => lhs.checked_div(rhs),
+                BinaryOp::Rem => lhs.checked_rem(rhs),
+            }
+            .ok_or_else(|| {
+                anyhow!(
+                    "Can't calculate {} {} {}, the result is out of range or divides by zero",
+                    self,
+                    op,
+                    other
+                )
+            })?;
+
+            // A `U64` that goes below zero becomes an `Int`.
+            return Ok(match (self, other) {
+                (Int(_), Int(_)) => Int(result.try_into()?),
+                _ => match result.try_into() {
+                    Ok(result) => U64(result),
+                    Err(_) => Int(result.try_into()?),
+                },
+            });
+        }
+
+        let (lhs, rhs) = match (self.as_f64(), other.as_f64()) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => anyhow::bail!(
+                "Can't calculate {} {} {}, both sides must be numbers",
+                self,
+                op,
+                other
+            ),
+        };
+
+        let result = match op {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+            BinaryOp::Rem => lhs % rhs,
+        };
+
+        Ok(Float(result as f32))
+    }
+
+    /// Arithmetic on dates and durations, `None` if neither side is one of them. The inner
+    /// `None` means the operator can't be applied or the result is out of range.
+    fn temporal_arithmetic(&self, op: BinaryOp, other: &Variable) -> Option<Option<Variable>> {
+        use Variable::*;
+
+        let scale = |duration: &chrono::Duration, factor: f64| {
+            let milliseconds = duration.num_milliseconds() as f64 * factor;
+            if milliseconds.is_finite() && milliseconds.abs() < i64::MAX as f64 {
+                Some(Duration(chrono::Duration::milliseconds(
+                    milliseconds as i64,
+                )))
+            } else {
+                None
+            }
+        };
+
+        let result = match (self, op, other) {
+            (DateTime(lhs), BinaryOp::Sub, DateTime(rhs)) => Some(Duration(*lhs - *rhs)),
+            (DateTime(date_time), BinaryOp::Add, Duration(duration))
+            | (Duration(duration), BinaryOp::Add, DateTime(date_time)) => {
+                date_time.checked_add_signed(*duration).map(DateTime)
+            }
+            (DateTime(date_time), BinaryOp::Sub, Duration(duration)) => {
+                date_time.checked_sub_signed(*duration).map(DateTime)
+            }
+            (Duration(lhs), BinaryOp::Add, Duration(rhs)) => lhs.checked_add(rhs).map(Duration),
+            (Duration(lhs), BinaryOp::Sub, Duration(rhs)) => lhs.checked_sub(rhs).map(Duration),
+            (Duration(duration), BinaryOp::Mul, number)
+            | (number, BinaryOp::Mul, Duration(duration)) => {
+                number.as_f64().and_then(|factor| scale(duration, factor))
+            }
+            (Duration(duration), BinaryOp::Div, number) => number
+                .as_f64()
+                .filter(|divisor| *divisor != 0.0)
+                .and_then(|divisor| scale(duration, 1.0 / divisor)),
+            (DateTime(_) | Duration(_), _, _) | (_, _, DateTime(_) | Duration(_)) => None,
+            _ => return None,
+        };
+
+        Some(result)
+    }
+
+    fn parse_like(string: &str, like: &Variable) -> Option<Variable> {
+        match like {
+            Variable::U64(_) => string.parse().ok().map(Variable::U64),
+            Variable::Float(_) => string.parse().ok().map(Variable::Float),
+            _ => string.parse().ok().map(Variable::Int),
+        }
     }
 }
 
-impl From<Instruction> for Box<dyn Executable> {
-    fn from(instruction: Instruction) -> Self {
-        Box::new(instruction)
+/// Executes a parsed `Program` by walking its statements. It holds nothing but the
+/// program, everything the rule keeps between executions is in the `ExecutionContext`
+/// that is passed in.
+pub struct Interpreter {
+    program: Program,
+}
+
+impl Interpreter {
+    pub fn new(program: Program) -> Self {
+        Self { program }
+    }
+
+    /// When the program has to be executed next, given that it was last executed at
+    /// `after`. `None` if none of its schedules is ever due again.
+    pub fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.program
+            .schedules
+            .iter()
+            .filter_map(|schedule| schedule.next_run(after))
+            .min()
+    }
+
+    /// The triggers the `ON` blocks of the program run on, `trigger` does nothing for the
+    /// others.
+    pub fn triggers(&self) -> impl Iterator<Item = Trigger> + '_ {
+        self.program.handlers.iter().map(|handler| handler.trigger)
+    }
+
+    /// Runs the `ON` blocks of `trigger`. Every `ON` block counts its runs on its own, so
+    /// they don't interrupt `IF ... FOR` conditions of the scheduled body and the other way
+    /// round.
+    pub fn trigger(
+        &mut self,
+        trigger: Trigger,
+        context: &mut ExecutionContext,
+    ) -> anyhow::Result<()> {
+        for (index, handler) in self.program.handlers.iter().enumerate() {
+            if handler.trigger == trigger {
+                self.evaluator(context, Body::Handler(index))
+                    .run(&handler.body);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the `PERSIST`ed variables their stored values or their defaults, without
+    /// running the rest of the body. Meant to be called once when the rule is loaded, so
+    /// that `ON` blocks that run before the body see them as well.
+    pub fn restore(&self, context: &mut ExecutionContext) {
+        let run = context
+            .runs
+            .get(&Body::Program)
+            .copied()
+            .unwrap_or_default();
+        let mut evaluator = self.evaluator_at(context, Body::Program, run);
+        evaluator.set_time();
+        for stmt in &self.program.body {
+            if let Stmt::Persist {
+                name,
+                default,
+                reset,
+            } = stmt
+            {
+                if let Err(err) = evaluator.persist(name, default, *reset) {
+                    error!("{}", err);
+                }
+            }
+        }
+    }
+
+    /// Starts the next run of `body`.
+    fn evaluator<'a>(&'a self, context: &'a mut ExecutionContext, body: Body) -> Evaluator<'a> {
+        let run = context.runs.entry(body).or_default();
+        *run += 1;
+        let run = *run;
+        self.evaluator_at(context, body, run)
+    }
+
+    fn evaluator_at<'a>(
+        &'a self,
+        context: &'a mut ExecutionContext,
+        body: Body,
+        run: u64,
+    ) -> Evaluator<'a> {
+        Evaluator {
+            procedures: &self.program.procedures,
+            context,
+            body,
+            run,
+            scopes: vec![],
+            depth: 0,
+            now: Local::now(),
+        }
     }
 }
 
-impl Executable for Instruction {
-    fn execute(&mut self) -> anyhow::Result<()> {
-        (self.0)()?;
+impl Executable for Interpreter {
+    fn execute(&mut self, context: &mut ExecutionContext) -> anyhow::Result<()> {
+        self.evaluator(context, Body::Program)
+            .run(&self.program.body);
         Ok(())
     }
 }
 
-pub struct Iterative {
-    executables: Vec<Box<dyn Executable>>,
-    key: String,
-    variable_map: *mut VariableMapType,
+/// What to do after a statement, `BREAK` and `CONTINUE` skip the rest of a loop body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    Break,
+    Continue,
+}
+
+enum Scope {
+    /// The parameters and the variables created with `SET` of a procedure.
+    Procedure(VariableMapType),
+    /// The fields of the element that `ITERATE` is at, or the element itself under the
+    /// name given with `ITERATE <name> IN`.
+    Element(VariableMapType),
+    /// The named groups the `MATCH`es of the condition of an `IF` branch captured.
+    Captures(VariableMapType),
 }
 
-impl Iterative {
-    pub fn new(key: String, variable_map: *mut VariableMapType) -> Self {
-        Self {
-            key,
-            variable_map,
-            executables: vec![],
+/// Walks the statements of one execution.
+struct Evaluator<'a> {
+    procedures: &'a FxHashMap<String, Arc<Procedure>>,
+    context: &'a mut ExecutionContext,
+    /// What is running and how often it has run, including this run.
+    body: Body,
+    run: u64,
+    /// The variables of every procedure that is currently being called and of every
+    /// element that is being iterated, the last one is the innermost.
+    scopes: Vec<Scope>,
+    /// How many `CALL`s are nested right now.
+    depth: usize,
+    /// The time at the start of the current execution, the value of `NOW`.
+    now: DateTime<Local>,
+}
+
+impl Evaluator<'_> {
+    fn variables(&self) -> &VariableMapType {
+        &self.context.variables
+    }
+
+    fn variables_mut(&mut self) -> &mut VariableMapType {
+        &mut self.context.variables
+    }
+
+    /// Runs the top level statements of the program or of an `ON` block, a statement that
+    /// fails doesn't stop the ones after it.
+    fn run(&mut self, body: &[Stmt]) {
+        self.set_time();
+        for stmt in body {
+            if let Err(err) = self.execute(stmt) {
+                error!("{}", err);
+            }
+        }
+        if let Err(err) = self.save_state() {
+            error!("{}", err);
         }
     }
 
-    pub fn change_key(&mut self, key: String) {
-        self.key = key;
+    fn save_state(&mut self) -> anyhow::Result<()> {
+        self.context.state.save(&self.context.variables)
     }
 
-    pub fn push(&mut self, executable: impl Into<Box<dyn Executable>>) {
-        self.executables.push(executable.into());
+    /// Sets `NOW` and the variables derived from it, they stay the same during one
+    /// execution.
+    fn set_time(&mut self) {
+        let now = Local::now();
+        // Midnight doesn't exist on some days in some time zones, the day then starts later.
+        let today = Local
+            .from_local_datetime(&now.naive_local().date().and_hms(0, 0, 0))
+            .earliest()
+            .unwrap_or(now);
+
+        self.now = now;
+        let map = self.variables_mut();
+        map.insert("NOW".into(), now.into());
+        map.insert("TODAY".into(), today.into());
+        map.insert("HOUR".into(), (now.hour() as usize).into());
+        map.insert("MINUTE".into(), (now.minute() as usize).into());
+        map.insert(
+            "WEEKDAY".into(),
+            now.format("%A").to_string().to_uppercase().into(),
+        );
+        map.insert("DATE".into(), now.format("%Y-%m-%d").to_string().into());
     }
-}
 
-impl From<Iterative> for Box<dyn Executable> {
-    fn from(iterative: Iterative) -> Self {
-        Box::new(iterative)
+    /// Looks `name` up in the elements that are being iterated, the captured groups and the
+    /// scope of the current procedure first, then in the globals. Procedures can't see the
+    /// variables of their callers.
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        for scope in self.scopes.iter().rev() {
+            match scope {
+                Scope::Element(variables) | Scope::Captures(variables) => {
+                    if let Some(variable) = variables.get(name) {
+                        return Some(variable);
+                    }
+                }
+                Scope::Procedure(variables) => {
+                    return variables.get(name).or_else(|| self.variables().get(name))
+                }
+            }
+        }
+        self.variables().get(name)
     }
-}
 
-impl Executable for Iterative {
-    fn execute(&mut self) -> anyhow::Result<()> {
-        debug!("Executing");
-        let variable_map = unsafe { &mut *self.variable_map };
+    /// `name.field`, `None` if the variable or the field isn't set.
+    fn field(&self, name: &str, field: &str) -> anyhow::Result<Option<&Variable>> {
+        match self.lookup(name) {
+            Some(Variable::Map(map)) => Ok(map.get(field)),
+            Some(variable) => anyhow::bail!("{} is {}, only windows have fields", name, variable),
+            None => Ok(None),
+        }
+    }
 
-        let vec = match unsafe { &mut *self.variable_map }.get(self.key.as_str()) {
-            Some(vec) => match vec {
-                Variable::Vector(vec) => vec,
-                _ => anyhow::bail!("The Value attained with Key {} is not a Vector", self.key),
-            },
-            None => anyhow::bail!("Value with Key {} does not exist", self.key),
+    fn execute_block(&mut self, body: &[Stmt]) -> anyhow::Result<Flow> {
+        for stmt in body {
+            match self.execute(stmt)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// The elements `selection` goes through.
+    fn elements(&self, selection: &Selection) -> anyhow::Result<Vec<Variable>> {
+        let key = &selection.key;
+        match self.lookup(key) {
+            Some(Variable::Vector(vec)) => Ok(vec.to_vec()),
+            Some(_) => anyhow::bail!("The Value attained with Key {} is not a Vector", key),
+            None => anyhow::bail!("Value with Key {} does not exist", key),
+        }
+    }
+
+    /// Pushes the scope of `element` and checks the `WHERE` condition of `selection`. The
+    /// scope is only left on the stack if the element is selected, the caller has to pop it
+    /// when it's done with the element.
+    fn enter(&mut self, selection: &Selection, element: &Variable) -> anyhow::Result<bool> {
+        let map = match element {
+            Variable::Map(map) => map,
+            _ => anyhow::bail!("The Value attained with Key {} is not a Map", selection.key),
         };
 
-        for (_i, variable) in vec.iter().enumerate() {
-            let map = match variable {
-                Variable::Map(map) => map,
-                _ => anyhow::bail!("The Value attained with Key {} is not a Map", self.key),
-            };
+        let scope = match &selection.binding {
+            Some(binding) => {
+                let mut scope = VariableMapType::default();
+                scope.insert(binding.clone().into(), element.clone());
+                scope
+            }
+            None => (**map).clone(),
+        };
+        self.scopes.push(Scope::Element(scope));
 
-            for (key, variable) in map.iter() {
-                variable_map.insert(key.clone(), variable.clone());
+        let selected = match &selection.filter {
+            Some(filter) => self.condition(filter),
+            None => Ok(true),
+        };
+        if !matches!(selected, Ok(true)) {
+            self.scopes.pop();
+        }
+        selected
+    }
+
+    /// Runs the body of `ITERATE` for the element in the innermost scope. A statement that
+    /// fails doesn't stop the ones after it.
+    fn execute_element(&mut self, body: &[Stmt]) -> anyhow::Result<Flow> {
+        for stmt in body {
+            match self.execute(stmt) {
+                Ok(Flow::Next) => {}
+                Ok(flow) => return Ok(flow),
+                Err(err) => error!("{}", err),
             }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> anyhow::Result<Flow> {
+        match stmt {
+            Stmt::If {
+                branches,
+                otherwise,
+            } => {
+                for branch in branches {
+                    if self.takes(branch)? {
+                        let mut captures = VariableMapType::default();
+                        self.capture(&branch.condition, &mut captures)?;
+                        if captures.is_empty() {
+                            return self.execute_block(&branch.body);
+                        }
 
-            for executable in &mut self.executables {
-                if let Err(err) = executable.execute() {
-                    println!("{}", err);
+                        self.scopes.push(Scope::Captures(captures));
+                        let flow = self.execute_block(&branch.body);
+                        self.scopes.pop();
+                        return flow;
+                    }
                 }
+
+                if let Some(otherwise) = otherwise {
+                    return self.execute_block(otherwise);
+                }
+
+                debug!("Didn't execute anything");
             }
+            Stmt::Iterate { selection, body } => {
+                debug!("Executing");
+
+                for variable in self.elements(selection)?.iter() {
+                    if !self.enter(selection, variable)? {
+                        continue;
+                    }
+                    let flow = self.execute_element(body);
+                    self.scopes.pop();
+
+                    if flow? == Flow::Break {
+                        break;
+                    }
+                }
+            }
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Continue => return Ok(Flow::Continue),
+            Stmt::Set { name, value } => {
+                let value = self.evaluate(value)?;
+                let name: Cow<'static, str> = name.clone().into();
+                // A field of an element or a captured group is only changed for the element
+                // or the branch. Inside a procedure SET changes a local variable or a global
+                // that exists already, anything else becomes a local variable.
+                for scope in self.scopes.iter_mut().rev() {
+                    match scope {
+                        Scope::Element(variables) | Scope::Captures(variables)
+                            if variables.contains_key(&name) =>
+                        {
+                            variables.insert(name, value);
+                            return Ok(Flow::Next);
+                        }
+                        Scope::Element(_) | Scope::Captures(_) => {}
+                        Scope::Procedure(variables)
+                            if variables.contains_key(&name)
+                                || !self.context.variables.contains_key(&name) =>
+                        {
+                            variables.insert(name, value);
+                            return Ok(Flow::Next);
+                        }
+                        Scope::Procedure(_) => break,
+                    }
+                }
+                self.variables_mut().insert(name, value);
+            }
+            Stmt::Call { name, args } => {
+                if self.depth >= MAX_CALL_DEPTH {
+                    anyhow::bail!(
+                        "Calling {} exceeded the maximum depth of {} nested calls, does it call itself endlessly?",
+                        name,
+                        MAX_CALL_DEPTH
+                    );
+                }
+
+                let procedure = match self.procedures.get(name) {
+                    Some(procedure) => procedure.clone(),
+                    None => anyhow::bail!("The procedure {} doesn't exist", name),
+                };
+
+                let mut scope = VariableMapType::default();
+                for (param, arg) in procedure.params.iter().zip(args) {
+                    scope.insert(param.clone().into(), self.evaluate(arg)?);
+                }
+
+                self.scopes.push(Scope::Procedure(scope));
+                self.depth += 1;
+                let result = self.execute_block(&procedure.body);
+                self.depth -= 1;
+                self.scopes.pop();
+                result?;
+            }
+            Stmt::Print(expr) => {
+                println!("{}", self.evaluate(expr)?);
+            }
+            Stmt::Log { level, message } => {
+                let message = self.evaluate(message)?;
+                // Makes it possible to filter by rule, e.g. `RUST_LOG=<rule id>=debug`.
+                log!(target: &self.context.rule.id, *level, "{}", message);
+            }
+            Stmt::Tag { category, value } => {
+                let category = self.evaluate(category)?.to_string();
+                let value = self.evaluate(value)?.to_string();
+                if category.is_empty() || category.contains(':') {
+                    anyhow::bail!(
+                        "The category of a tag can't be empty or contain `:`, got `{}`",
+                        category
+                    );
+                }
+                let tag = format!("{}:{}", category, value);
+                if !self.context.tags.contains(&tag) {
+                    self.context.tags.push(tag);
+                }
+            }
+            Stmt::SetMeta { key, value } => {
+                let value = Value::from(&self.evaluate(value)?);
+                self.context.meta.insert(key.clone(), value);
+            }
+            Stmt::AssignProject(project) => {
+                let project = self.evaluate(project)?.to_string();
+                self.context.project = Some(self.project_id(&project)?);
+            }
+            Stmt::Notify {
+                title,
+                body,
+                urgency,
+                timeout,
+            } => notify(&Notification {
+                title: self.evaluate(title)?.to_string(),
+                body: self.evaluate(body)?.to_string(),
+                urgency: *urgency,
+                timeout: *timeout,
+            })?,
+            Stmt::Run { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg).map(|arg| arg.to_string()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let output = run_command(name, &args)?;
+
+                let map = self.variables_mut();
+                map.insert(
+                    "RUN_STDOUT".into(),
+                    output.stdout.trim_end_matches(['\r', '\n']).into(),
+                );
+                map.insert("RUN_EXIT_CODE".into(), i64::from(output.exit_code).into());
+            }
+            Stmt::HttpPost { url, body } => {
+                let url = self.evaluate(url)?.to_string();
+                let body = self.json(body)?;
+                post_json(&url, &body)?;
+            }
+            Stmt::Persist {
+                name,
+                default,
+                reset,
+            } => self.persist(name, default, *reset)?,
+            Stmt::SaveToDb => self.save_to_db()?,
+            Stmt::GetNetworkSsid => {
+                if let Some(ssid) = get_network_ssid() {
+                    self.variables_mut()
+                        .insert("NETWORK_SSID".into(), ssid.into());
+                }
+            }
+            Stmt::GetPeripherals => {
+                let map = self.variables_mut();
+                map.insert(
+                    "KEYSTROKES".into(),
+                    KEYSTROKES.load(atomic::Ordering::Relaxed).into(),
+                );
+                map.insert(
+                    "MOUSE_CLICKS".into(),
+                    MOUSE_CLICKS.load(atomic::Ordering::Relaxed).into(),
+                );
+            }
+            Stmt::GetWindows => {
+                let event = self.context.capturer.lock().unwrap().capture()?;
+                let map = self.variables_mut();
+                map.insert(
+                    "WINDOWS".into(),
+                    event
+                        .windows
+                        .into_iter()
+                        .map(|w| w.into())
+                        .collect::<Vec<VariableMapType>>()
+                        .into(),
+                );
+                map.insert(
+                    "SECONDS_SINCE_LAST_INPUT".into(),
+                    event.seconds_since_last_input.into(),
+                );
+            }
+            Stmt::CaptureScreen(screens) => self.capture_screen(*screens)?,
+        };
+
+        Ok(Flow::Next)
+    }
+
+    /// Whether the branch of an `IF` is taken. A condition with `FOR` has to hold in every
+    /// run of the program, or of the `ON` block it's in, for the whole duration. Inside
+    /// `ITERATE` it's enough if it holds for one of the elements in every run.
+    fn takes(&mut self, branch: &Branch) -> anyhow::Result<bool> {
+        let holds = self.condition(&branch.condition)?;
+        let sustained = match &branch.sustained {
+            Some(sustained) if holds => sustained,
+            _ => return Ok(holds),
+        };
+
+        // A run in which the condition didn't hold isn't recorded, the next run in which it
+        // holds starts the wait over.
+        let (run, now) = (self.run, self.now);
+        let (last, since) = self
+            .context
+            .sustained
+            .entry((self.body, sustained.id))
+            .or_insert((run, now));
+        if *last + 1 < run {
+            *since = now;
         }
+        *last = run;
 
+        Ok(now - *since >= sustained.duration)
+    }
+
+    /// Collects the named groups of the `MATCH`es in `condition`, which has held. A group
+    /// that didn't take part in the match, e.g. because the other side of an `OR` held, is
+    /// set to an empty text so that the branch can rely on it.
+    fn capture(&mut self, condition: &Expr, captures: &mut VariableMapType) -> anyhow::Result<()> {
+        match condition {
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                self.capture(lhs, captures)?;
+                self.capture(rhs, captures)?;
+            }
+            Expr::Match { value, regexes } => {
+                let value = self.operand(value)?;
+                let value = value
+                    .as_ref()
+                    .and_then(Variable::as_str)
+                    .unwrap_or_default();
+                let found = regexes.iter().find_map(|regex| regex.captures(value));
+                for regex in regexes {
+                    for name in regex.capture_names().flatten() {
+                        let group = found
+                            .as_ref()
+                            .and_then(|found| found.name(name))
+                            .map_or("", |group| group.as_str());
+                        // A group that captured something wins over one that didn't.
+                        if !group.is_empty() || !captures.contains_key(name) {
+                            captures.insert(name.to_owned().into(), group.into());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
         Ok(())
     }
-}
 
-#[derive(Default)]
-pub struct Conditional {
-    pub conditions: Vec<Vec<ConditionalFn>>,
-    pub executables: Vec<Box<dyn Executable>>,
-    pub else_if_conditionals: Option<Vec<Conditional>>,
-    pub else_executables: Option<Vec<Box<dyn Executable>>>,
-}
+    fn condition(&mut self, expr: &Expr) -> anyhow::Result<bool> {
+        match self.evaluate(expr)? {
+            Variable::Bool(boolean) => Ok(boolean),
+            variable => anyhow::bail!("Expected a condition, got {}", variable),
+        }
+    }
 
-impl Executable for Conditional {
-    fn execute(&mut self) -> anyhow::Result<()> {
-        for conditions in &mut self.conditions {
-            let mut should_execute = false;
+    /// Like `evaluate`, but a variable that isn't set results in `None` instead of an error.
+    fn operand(&mut self, expr: &Expr) -> anyhow::Result<Option<Variable>> {
+        match expr {
+            Expr::Variable(name) => Ok(self.lookup(name).cloned()),
+            Expr::Field { name, field } => Ok(self.field(name, field)?.cloned()),
+            _ => self.evaluate(expr).map(Some),
+        }
+    }
 
-            for condition in conditions {
-                should_execute = condition();
-            }
+    fn json(&mut self, json: &Json) -> anyhow::Result<Value> {
+        let value = match json {
+            Json::Object(object) => Value::Object(
+                object
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), self.json(value)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Json::Array(array) => Value::Array(
+                array
+                    .iter()
+                    .map(|value| self.json(value))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Json::Value(expr) => Value::from(&self.evaluate(expr)?),
+        };
+        Ok(value)
+    }
 
-            if should_execute {
-                for instruction in &mut self.executables {
-                    instruction.execute()?;
+    fn evaluate(&mut self, expr: &Expr) -> anyhow::Result<Variable> {
+        match expr {
+            Expr::Str(string) => Ok(string.as_str().into()),
+            Expr::Int(int) => Ok((*int).into()),
+            Expr::Float(float) => Ok((*float).into()),
+            Expr::Duration(duration) => Ok((*duration).into()),
+            Expr::Format(segments) => {
+                let mut string = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => string.push_str(literal),
+                        Segment::Variable { name, spec } => {
+                            let variable = match name.split_once('.') {
+                                Some((name, field)) => self.field(name, field)?,
+                                None => self.lookup(name),
+                            }
+                            .ok_or_else(|| {
+                                anyhow!("Couldn't find the Variable with Key {}", name)
+                            })?;
+                            string.push_str(&format_variable(variable, spec));
+                        }
+                    }
                 }
-                return Ok(());
+                Ok(string.into())
+            }
+            Expr::Variable(name) => self
+                .lookup(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Couldn't find the Variable with Key {}", name)),
+            Expr::Field { name, field } => self
+                .field(name, field)?
+                .cloned()
+                .ok_or_else(|| anyhow!("Couldn't find the field {} of {}", field, name)),
+            Expr::Aggregate {
+                function,
+                selection,
+            } => self.aggregate(function, selection),
+            Expr::Function { function, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                function.call(&args)
+            }
+            Expr::Array(elements) => Ok(Variable::Vector(Box::new(
+                elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<anyhow::Result<_>>()?,
+            ))),
+            Expr::Not(expr) => Ok((!self.condition(expr)?).into()),
+            Expr::And(lhs, rhs) => Ok((self.condition(lhs)? && self.condition(rhs)?).into()),
+            Expr::Or(lhs, rhs) => Ok((self.condition(lhs)? || self.condition(rhs)?).into()),
+            Expr::Compare { op, lhs, rhs } => {
+                let (lhs, rhs) = match (self.operand(lhs)?, self.operand(rhs)?) {
+                    (Some(lhs), Some(rhs)) => (lhs, rhs),
+                    // A comparison with a variable that isn't set, e.g. NETWORK_SSID
+                    // while offline, doesn't hold.
+                    _ => return Ok(false.into()),
+                };
+
+                let result = match op {
+                    CompareOp::Eq => lhs.loosely_eq(&rhs),
+                    CompareOp::Bigger => lhs.loosely_cmp(&rhs) == Some(Ordering::Greater),
+                    CompareOp::Lesser => lhs.loosely_cmp(&rhs) == Some(Ordering::Less),
+                    CompareOp::In => match &rhs {
+                        Variable::Vector(vec) => {
+                            vec.iter().any(|variable| lhs.loosely_eq(variable))
+                        }
+                        _ => false,
+                    },
+                };
+
+                Ok(result.into())
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                self.evaluate(lhs)?.arithmetic(*op, &self.evaluate(rhs)?)
+            }
+            Expr::Match { value, regexes } => {
+                let value = self.operand(value)?;
+                let result = match value.as_ref().and_then(Variable::as_str) {
+                    Some(string) => regexes.iter().any(|regex| regex.is_match(string)),
+                    None => false,
+                };
+                Ok(result.into())
             }
         }
+    }
+
+    fn aggregate(
+        &mut self,
+        function: &Aggregate,
+        selection: &Selection,
+    ) -> anyhow::Result<Variable> {
+        let elements = self.elements(selection)?;
 
-        if let Some(else_if_conditionals) = &mut self.else_if_conditionals {
-            for conditional in else_if_conditionals {
-                if conditional.execute().is_ok() {
-                    return Ok(());
+        let mut selected = vec![];
+        for element in elements.iter() {
+            if !self.enter(selection, element)? {
+                continue;
+            }
+            self.scopes.pop();
+
+            match (function, element) {
+                // An element without the field adds nothing.
+                (Aggregate::Sum(field), Variable::Map(map)) => {
+                    selected.extend(map.get(field.as_str()).cloned())
                 }
+                _ => selected.push(element.clone()),
+            }
+            // Nothing else has to be looked at.
+            if matches!(function, Aggregate::Any | Aggregate::First) {
+                break;
             }
-        } else if let Some(else_executables) = &mut self.else_executables {
-            for instruction in else_executables {
-                instruction.execute()?;
+        }
+
+        let result = match function {
+            Aggregate::Count => selected.len().into(),
+            Aggregate::Any => (!selected.is_empty()).into(),
+            Aggregate::All => (selected.len() == elements.len()).into(),
+            Aggregate::First => selected
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| VariableMapType::default().into()),
+            Aggregate::Sum(field) => {
+                let zero: Variable = match window_field_type(field) {
+                    Some(Type::Duration) => Duration::zero().into(),
+                    Some(Type::Float) => 0.0_f32.into(),
+                    _ => 0_usize.into(),
+                };
+                selected
+                    .iter()
+                    .try_fold(zero, |sum, value| sum.arithmetic(BinaryOp::Add, value))?
             }
-            return Ok(());
+        };
+        Ok(result)
+    }
+
+    /// The id of the project of the user with the id or the name `project`, names are
+    /// compared regardless of the case.
+    fn project_id(&self, project: &str) -> anyhow::Result<String> {
+        let projects = &self.context.projects;
+        if projects.is_empty() {
+            anyhow::bail!("The projects of the user aren't known");
         }
 
-        debug!("Didn't execute anything");
+        match find_project(projects, project) {
+            Some(project) => Ok(project.id.clone()),
+            None => {
+                let names: Vec<_> = projects.iter().map(|project| &*project.name).collect();
+                anyhow::bail!(
+                    "There's no project with the name or the id `{}`, the projects are: {}",
+                    project,
+                    names.join(", ")
+                )
+            }
+        }
+    }
 
+    fn persist(&mut self, name: &str, default: &Expr, reset: Option<Reset>) -> anyhow::Result<()> {
+        let value = match self
+            .context
+            .state
+            .persist(&self.context.rule.id, name, reset, self.now)
+        {
+            Persisted::Current => return Ok(()),
+            Persisted::Restored(value) => value,
+            Persisted::Default => match self.evaluate(default) {
+                Ok(value) => value,
+                Err(err) => {
+                    self.context.state.forget(name);
+                    return Err(err);
+                }
+            },
+        };
+        self.variables_mut().insert(name.to_owned().into(), value);
         Ok(())
     }
+
+    fn save_to_db(&mut self) -> anyhow::Result<()> {
+        debug!("Saving to DB");
+
+        // Saved before the event is sent, so that a crash can't leave the state behind what
+        // is already in the database.
+        self.save_state()?;
+
+        let tags = self.context.tags.clone();
+        let meta = self.context.meta.clone();
+        let project = self.context.project.clone();
+        let rule = self.context.rule.clone();
+
+        let map = self.variables_mut();
+
+        let seconds_since_last_input = match map.get("SECONDS_SINCE_LAST_INPUT") {
+            Some(Variable::U64(int)) => *int,
+            _ => anyhow::bail!("SECONDS_SINCE_LAST_INPUT is not a U64"),
+        };
+
+        let windows: Vec<Window> = match map.get("WINDOWS") {
+            Some(Variable::Vector(vec)) => {
+                let mut windows = vec![];
+                for variable in vec.iter() {
+                    let map = match variable {
+                        Variable::Map(map) => map,
+                        _ => anyhow::bail!("Variable is not a Map"),
+                    };
+                    windows.push((&**map).try_into()?);
+                }
+                windows
+            }
+            _ => anyhow::bail!("WINDOWS is not a Vector"),
+        };
+
+        let screenshots: Option<Box<Vec<Value>>> = match map.get("SCREENSHOTS") {
+            Some(Variable::SerdeJsonVector(value)) => Some(value.clone()),
+            _ => None,
+        };
+
+        let network: Option<String> = match map.get("NETWORK_SSID") {
+            Some(Variable::ArcStr(string)) => match get_or_insert_user_ssid(string.as_str()) {
+                Ok(id) => Some(id),
+                Err(err) => {
+                    error!("{}", err);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let event = Event {
+            windows,
+            rule: Some(rule),
+            project,
+            network,
+            screenshots,
+            keyboard: KEYSTROKES.load(atomic::Ordering::Relaxed),
+            mouse: MOUSE_CLICKS.load(atomic::Ordering::Relaxed),
+            seconds_since_last_input,
+            tags,
+            meta,
+        };
+
+        KEYSTROKES.store(0, atomic::Ordering::SeqCst);
+        MOUSE_CLICKS.store(0, atomic::Ordering::SeqCst);
+
+        send_user_event(event)?;
+
+        if let Some(Variable::SerdeJsonVector(screenshots)) = map.get_mut("SCREENSHOTS") {
+            // Clear the screenshots, so that we don't repeat them on the next save.
+            screenshots.clear();
+        }
+
+        // The tags and the metadata belong to the event that was just sent.
+        self.context.tags.clear();
+        self.context.meta.clear();
+        self.context.project = None;
+
+        Ok(())
+    }
+
+    fn capture_screen(&mut self, screens: Screens) -> anyhow::Result<()> {
+        let capturer = match &self.context.screen_capturer {
+            Some(capturer) => capturer,
+            None => {
+                let capturer = captis::init_capturer()
+                    .map_err(|_| anyhow!("Couldn't initiate Screen capturer"))?;
+                self.context.screen_capturer.insert(Box::new(capturer))
+            }
+        };
+
+        let images = match screens {
+            Screens::All => capturer.capture_all()?,
+            Screens::Primary => vec![capturer.capture_primary()?],
+        };
+
+        let mut file_vec = send_screenshots(&images)?;
+
+        match self
+            .variables_mut()
+            .entry("SCREENSHOTS".into())
+            .or_insert_with(|| Variable::SerdeJsonVector(Box::default()))
+        {
+            Variable::SerdeJsonVector(vec) => vec.append(&mut file_vec),
+            _ => anyhow::bail!("Variable is not a SerdeJsonVector"),
+        };
+
+        Ok(())
+    }
+}
+
+/// Applies a format specifier the way `format!` would, numbers are aligned to the right and
+/// everything else to the left unless specified otherwise.
+fn format_variable(variable: &Variable, spec: &FormatSpec) -> String {
+    let numeric = variable.as_f64().is_some();
+
+    let mut string = match (variable, &spec.pattern, spec.precision) {
+        (Variable::DateTime(date_time), Some(pattern), _) => date_time.format(pattern).to_string(),
+        (Variable::Float(float), _, Some(precision)) => format!("{:.*}", precision, float),
+        (_, _, Some(precision)) if !numeric => {
+            variable.to_string().chars().take(precision).collect()
+        }
+        _ => variable.to_string(),
+    };
+
+    let padding = spec
+        .width
+        .unwrap_or(0)
+        .saturating_sub(string.chars().count());
+
+    if padding == 0 {
+        return string;
+    }
+
+    if spec.zero && numeric {
+        // The zeros go between the sign and the digits.
+        let sign = if string.starts_with('-') {
+            string.remove(0);
+            "-"
+        } else {
+            ""
+        };
+        return format!("{}{}{}", sign, "0".repeat(padding), string);
+    }
+
+    let fill = spec.fill.unwrap_or(' ').to_string();
+    let default = if numeric { Align::Right } else { Align::Left };
+    let (left, right) = match spec.align.unwrap_or(default) {
+        Align::Left => (0, padding),
+        Align::Center => (padding / 2, padding - padding / 2),
+        Align::Right => (padding, 0),
+    };
+
+    format!("{}{}{}", fill.repeat(left), string, fill.repeat(right))
 }
 
-impl From<Conditional> for Box<dyn Executable> {
-    fn from(conditional: Conditional) -> Self {
-        Box::new(conditional)
+/// Writes a duration the way people read it, e.g. `2h 5m 30s`.
+fn write_duration(f: &mut fmt::Formatter, duration: Duration) -> fmt::Result {
+    if duration < Duration::zero() {
+        write!(f, "-")?;
+    }
+
+    let total = duration.num_milliseconds().unsigned_abs();
+    let (seconds, milliseconds) = (total / 1000, total % 1000);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    if hours > 0 {
+        write!(f, "{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        write!(f, "{}m {}s", minutes, seconds)
+    } else if milliseconds == 0 {
+        write!(f, "{}s", seconds)
+    } else {
+        write!(f, "{}ms", total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capture::SharedCapturer,
+        graphql::Project,
+        scripting::{parse, state::State},
+    };
+    use std::{thread, time::Duration as StdDuration};
+
+    #[test]
+    fn sustained_conditions_of_on_blocks_count_their_own_runs() {
+        let (program, diagnostics) = parse(
+            "EVERY 1 HOURS
+SET TAKEN = TAKEN
+ON WINDOW_CHANGE
+  IF ACTIVE EQ 1 FOR 100 MILLISECONDS
+    SET TAKEN = TAKEN + 1
+  END
+END",
+        );
+        assert_eq!(diagnostics, []);
+        let mut interpreter = Interpreter::new(program.unwrap());
+        let rule = Rule {
+            id: "rule".into(),
+            body: String::new(),
+        };
+        let mut context = ExecutionContext::new(rule, SharedCapturer::new());
+        context.variables.insert("TAKEN".into(), 0usize.into());
+
+        let run = |active: usize, interpreter: &mut Interpreter, context: &mut ExecutionContext| {
+            context.variables.insert("ACTIVE".into(), active.into());
+            interpreter.trigger(Trigger::WindowChange, context).unwrap();
+            context.variables["TAKEN"].clone()
+        };
+        let wait = || thread::sleep(StdDuration::from_millis(150));
+
+        assert_eq!(run(1, &mut interpreter, &mut context), Variable::Int(0));
+        wait();
+        // A single run in which it doesn't hold starts the wait over.
+        assert_eq!(run(0, &mut interpreter, &mut context), Variable::Int(0));
+        assert_eq!(run(1, &mut interpreter, &mut context), Variable::Int(0));
+        // Executions of the program don't count as runs of the ON block.
+        interpreter.execute(&mut context).unwrap();
+        wait();
+        assert_eq!(run(1, &mut interpreter, &mut context), Variable::Int(1));
+    }
+
+    #[test]
+    fn on_blocks_see_persisted_variables_before_the_body_ran() {
+        let (program, diagnostics) = parse(
+            "EVERY 1 HOURS
+PERSIST TOTAL = 10
+ON WINDOW_CHANGE
+  SET TOTAL = TOTAL + 1
+END",
+        );
+        assert_eq!(diagnostics, []);
+        let mut interpreter = Interpreter::new(program.unwrap());
+        let rule = Rule {
+            id: "rule".into(),
+            body: String::new(),
+        };
+        let path =
+            std::env::temp_dir().join(format!("timetrackrs-restore-{}.json", std::process::id()));
+        let mut context = ExecutionContext::new(rule, SharedCapturer::new());
+        context.state = State::at(path.clone());
+
+        interpreter.restore(&mut context);
+        interpreter
+            .trigger(Trigger::WindowChange, &mut context)
+            .unwrap();
+        assert_eq!(context.variables["TOTAL"], Variable::Int(11));
+        // The body doesn't reset it to its default.
+        interpreter.execute(&mut context).unwrap();
+        assert_eq!(context.variables["TOTAL"], Variable::Int(11));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn projects_come_from_the_context() {
+        let (program, diagnostics) = parse(
+            "EVERY 1 HOURS
+SET PROJECTS = \"none\"
+ASSIGN_PROJECT LOWER(\"CLIENT X\")",
+        );
+        assert_eq!(diagnostics, []);
+        let rule = Rule {
+            id: "rule".into(),
+            body: String::new(),
+        };
+        let mut context = ExecutionContext::new(rule, SharedCapturer::new());
+        context.projects = vec![Project {
+            id: "1".into(),
+            name: "Client X".into(),
+        }];
+
+        Interpreter::new(program.unwrap())
+            .execute(&mut context)
+            .unwrap();
+        assert_eq!(context.project.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn whole_numbers_go_below_zero() {
+        let sub = |lhs: Variable, rhs: Variable| lhs.arithmetic(BinaryOp::Sub, &rhs).unwrap();
+        assert_eq!(sub(Variable::Int(3), Variable::Int(5)), Variable::Int(-2));
+        assert_eq!(sub(Variable::U64(3), Variable::Int(5)), Variable::Int(-2));
+        assert_eq!(sub(Variable::U64(5), Variable::Int(3)), Variable::U64(2));
+        assert_eq!(
+            Variable::Int(-7)
+                .arithmetic(BinaryOp::Div, &Variable::Int(2))
+                .unwrap(),
+            Variable::Int(-3)
+        );
+        assert!(Variable::Int(1)
+            .arithmetic(BinaryOp::Div, &Variable::Int(0))
+            .is_err());
+        assert_eq!(
+            Variable::Int(-1).loosely_cmp(&Variable::U64(0)),
+            Some(Ordering::Less)
+        );
+        assert!(Variable::Int(-1).loosely_eq(&"-1".into()));
+    }
+
+    fn context() -> ExecutionContext {
+        let rule = Rule {
+            id: "rule".into(),
+            body: String::new(),
+        };
+        ExecutionContext::new(rule, SharedCapturer::new())
+    }
+
+    /// Runs the body of `source` once in `context`, unlike `execute` it stops at the first
+    /// statement that fails and returns its error.
+    fn run(source: &str, context: &mut ExecutionContext) -> anyhow::Result<()> {
+        let (program, diagnostics) = parse(source);
+        assert_eq!(diagnostics, [], "{}", source);
+        let interpreter = Interpreter::new(program.unwrap());
+        let mut evaluator = interpreter.evaluator(context, Body::Program);
+        evaluator.set_time();
+        for stmt in &interpreter.program.body {
+            evaluator.execute(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// `WINDOWS` with the title, the process name, the memory and the uptime in minutes of
+    /// every window.
+    fn windows(windows: &[(&str, &str, i64, i64)]) -> Variable {
+        windows
+            .iter()
+            .map(|&(title, process_name, memory, uptime)| {
+                let mut window = VariableMapType::default();
+                window.insert("TITLE".into(), title.into());
+                window.insert("PROCESS_NAME".into(), process_name.into());
+                window.insert("MEMORY".into(), memory.into());
+                window.insert("UPTIME_OF_PROCESS".into(), Duration::minutes(uptime).into());
+                window.into()
+            })
+            .collect::<Vec<Variable>>()
+            .into()
+    }
+
+    fn text(string: &str) -> Variable {
+        string.into()
+    }
+
+    #[test]
+    fn break_and_continue_skip_the_rest_of_the_loop() {
+        let mut context = context();
+        context.variables.insert(
+            "WINDOWS".into(),
+            windows(&[
+                ("a", "editor", 1, 1),
+                ("b", "skip", 1, 1),
+                ("c", "editor", 1, 1),
+                ("d", "stop", 1, 1),
+                ("e", "editor", 1, 1),
+            ]),
+        );
+        run(
+            "EVERY 1 HOURS
+SET COUNT = 0
+SET LAST = \"\"
+ITERATE WINDOWS
+  IF PROCESS_NAME EQ \"skip\"
+    CONTINUE
+  END
+  IF PROCESS_NAME EQ \"stop\"
+    BREAK
+  END
+  SET COUNT = COUNT + 1
+  SET LAST = TITLE
+END",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["COUNT"], Variable::Int(2));
+        assert_eq!(context.variables["LAST"], text("c"));
+    }
+
+    #[test]
+    fn where_selects_the_elements_to_iterate() {
+        let mut context = context();
+        context.variables.insert(
+            "WINDOWS".into(),
+            windows(&[("a", "x", 50, 1), ("b", "x", 200, 1), ("c", "x", 300, 1)]),
+        );
+        run(
+            "EVERY 1 HOURS
+SET FIELDS = \"\"
+SET NAMED = \"\"
+ITERATE WINDOWS WHERE MEMORY BIGGER 100 AND TITLE NOT EQ \"c\"
+  SET FIELDS = TITLE
+END
+ITERATE W IN WINDOWS WHERE W.MEMORY LESSER 100
+  SET NAMED = W.TITLE
+END",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["FIELDS"], text("b"));
+        assert_eq!(context.variables["NAMED"], text("a"));
+    }
+
+    #[test]
+    fn named_elements_dont_hide_the_globals() {
+        let mut context = context();
+        context
+            .variables
+            .insert("WINDOWS".into(), windows(&[("window", "x", 1, 1)]));
+        context.variables.insert("TITLE".into(), text("global"));
+        run(
+            "EVERY 1 HOURS
+ITERATE W IN WINDOWS
+  SET ELEMENT = W.TITLE
+  SET BARE = TITLE
+END",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["ELEMENT"], text("window"));
+        assert_eq!(context.variables["BARE"], text("global"));
+    }
+
+    #[test]
+    fn window_fields_stay_inside_the_loop() {
+        let mut context = context();
+        context
+            .variables
+            .insert("WINDOWS".into(), windows(&[("window", "x", 1, 1)]));
+        context.variables.insert("TITLE".into(), text("global"));
+        run(
+            "EVERY 1 HOURS
+DEFINE TAG_TITLE()
+  TAG \"called\" TITLE
+END
+ITERATE WINDOWS
+  TAG \"loop\" TITLE
+  SET TITLE = \"changed\"
+  TAG \"changed\" TITLE
+  CALL TAG_TITLE()
+END
+TAG \"after\" TITLE",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(
+            context.tags,
+            [
+                "loop:window",
+                "changed:changed",
+                "called:global",
+                "after:global"
+            ]
+        );
+        assert_eq!(context.variables["TITLE"], text("global"));
+    }
+
+    #[test]
+    fn aggregates_sum_up_the_selected_windows() {
+        let mut context = context();
+        context.variables.insert(
+            "WINDOWS".into(),
+            windows(&[
+                ("inbox", "mail", 300, 90),
+                ("standup", "chat", 200, 5),
+                ("random", "chat", 100, 10),
+            ]),
+        );
+        run(
+            "EVERY 1 HOURS
+SET ALL_WINDOWS = COUNT(WINDOWS)
+SET CHATS = COUNT(W IN WINDOWS WHERE W.PROCESS_NAME EQ \"chat\")
+SET ANY_CHAT = ANY(WINDOWS WHERE PROCESS_NAME EQ \"chat\")
+SET ANY_GAME = ANY(WINDOWS WHERE PROCESS_NAME EQ \"game\")
+SET ALL_SMALL = ALL(WINDOWS WHERE MEMORY LESSER 1000)
+SET ALL_BIG = ALL(WINDOWS WHERE MEMORY BIGGER 150)
+SET CHAT = FIRST(WINDOWS WHERE PROCESS_NAME EQ \"chat\")
+SET CHAT_TITLE = CHAT.TITLE
+SET MEMORY_TOTAL = SUM(WINDOWS.MEMORY)
+SET CHAT_UPTIME = SUM(WINDOWS.UPTIME_OF_PROCESS WHERE PROCESS_NAME EQ \"chat\")
+SET GAME_UPTIME = SUM(WINDOWS.UPTIME_OF_PROCESS WHERE PROCESS_NAME EQ \"game\")",
+            &mut context,
+        )
+        .unwrap();
+
+        let variable = |name: &str| context.variables[name].clone();
+        assert_eq!(variable("ALL_WINDOWS"), Variable::Int(3));
+        assert_eq!(variable("CHATS"), Variable::Int(2));
+        assert_eq!(variable("ANY_CHAT"), Variable::Bool(true));
+        assert_eq!(variable("ANY_GAME"), Variable::Bool(false));
+        assert_eq!(variable("ALL_SMALL"), Variable::Bool(true));
+        assert_eq!(variable("ALL_BIG"), Variable::Bool(false));
+        assert_eq!(variable("CHAT_TITLE"), text("standup"));
+        assert_eq!(variable("MEMORY_TOTAL"), Variable::Int(600));
+        assert_eq!(
+            variable("CHAT_UPTIME"),
+            Variable::Duration(Duration::minutes(15))
+        );
+        assert_eq!(
+            variable("GAME_UPTIME"),
+            Variable::Duration(Duration::zero())
+        );
+    }
+
+    #[test]
+    fn first_without_a_match_has_no_fields() {
+        let mut context = context();
+        context
+            .variables
+            .insert("WINDOWS".into(), windows(&[("inbox", "mail", 300, 90)]));
+        run(
+            "EVERY 1 HOURS
+SET GAME = FIRST(WINDOWS WHERE PROCESS_NAME EQ \"game\")
+SET FOUND = GAME.TITLE EQ \"inbox\" OR GAME.TITLE MATCH \".*\"",
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(
+            context.variables["GAME"],
+            Variable::from(VariableMapType::default())
+        );
+        assert_eq!(context.variables["FOUND"], Variable::Bool(false));
+
+        let err = run(
+            "EVERY 1 HOURS
+SET GAME = FIRST(WINDOWS WHERE PROCESS_NAME EQ \"game\")
+SET TITLE_OF_GAME = GAME.TITLE",
+            &mut context,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Couldn't find the field TITLE of GAME");
+    }
+
+    #[test]
+    fn aggregates_over_no_windows() {
+        let mut context = context();
+        context.variables.insert("WINDOWS".into(), windows(&[]));
+        run(
+            "EVERY 1 HOURS
+SET NONE = COUNT(WINDOWS)
+SET ANY_WINDOW = ANY(WINDOWS)
+SET EVERY_WINDOW = ALL(WINDOWS WHERE MEMORY BIGGER 0)
+SET MEMORY_TOTAL = SUM(WINDOWS.MEMORY)",
+            &mut context,
+        )
+        .unwrap();
+
+        let variable = |name: &str| context.variables[name].clone();
+        assert_eq!(variable("NONE"), Variable::Int(0));
+        assert_eq!(variable("ANY_WINDOW"), Variable::Bool(false));
+        assert_eq!(variable("EVERY_WINDOW"), Variable::Bool(true));
+        assert_eq!(variable("MEMORY_TOTAL"), Variable::Int(0));
+    }
+
+    #[test]
+    fn captures_are_set_inside_the_matched_branch() {
+        let mut context = context();
+        run(
+            r#"EVERY 1 HOURS
+SET MESSAGE = "build 42 failed"
+SET PROJECT = "global"
+IF MESSAGE MATCH "^deploy (?P<PROJECT>[a-z]+)"
+  SET DEPLOYED = PROJECT
+ELSEIF MESSAGE MATCH "^build (?P<NUMBER>[0-9]+)"
+  SET IN_ELSEIF = PROJECT
+  SET BUILD = NUMBER
+END
+IF MESSAGE MATCH "^deploy (?P<PROJECT>[a-z]+)"
+  SET DEPLOYED = PROJECT
+ELSE
+  SET IN_ELSE = PROJECT
+END
+SET AFTER = PROJECT"#,
+            &mut context,
+        )
+        .unwrap();
+
+        assert!(!context.variables.contains_key("DEPLOYED"));
+        assert!(!context.variables.contains_key("NUMBER"));
+        assert_eq!(context.variables["IN_ELSEIF"], text("global"));
+        assert_eq!(context.variables["BUILD"], text("42"));
+        assert_eq!(context.variables["IN_ELSE"], text("global"));
+        assert_eq!(context.variables["AFTER"], text("global"));
+    }
+
+    #[test]
+    fn groups_on_the_other_side_of_or_are_empty() {
+        let mut context = context();
+        run(
+            r#"EVERY 1 HOURS
+SET MESSAGE = "build 42 failed"
+IF MESSAGE MATCH "^deploy (?P<PROJECT>[a-z]+)" OR MESSAGE MATCH "^build (?P<NUMBER>[0-9]+)"
+  SET DEPLOYED = PROJECT
+  SET BUILD = NUMBER
+END"#,
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["DEPLOYED"], text(""));
+        assert_eq!(context.variables["BUILD"], text("42"));
+    }
+
+    #[test]
+    fn not_binds_no_groups() {
+        let mut context = context();
+        run(
+            r#"EVERY 1 HOURS
+SET MESSAGE = "build 42 failed"
+SET PROJECT = "global"
+IF NOT MESSAGE MATCH "^deploy (?P<PROJECT>[a-z]+)"
+  SET SEEN = PROJECT
+END"#,
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["SEEN"], text("global"));
+    }
+
+    #[test]
+    fn the_first_matching_regex_of_match_in_sets_the_groups() {
+        let mut context = context();
+        run(
+            r#"EVERY 1 HOURS
+SET MESSAGE = "build 42 failed"
+IF MESSAGE MATCH IN ["^deploy (?P<WHAT>[a-z]+)", "^build (?P<WHAT>[0-9]+)", "(?P<WHAT>[a-z]+)$"]
+  SET SEEN = WHAT
+END"#,
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["SEEN"], text("42"));
+    }
+
+    #[test]
+    fn and_and_or_skip_the_right_hand_side_when_the_left_decides() {
+        let mut context = context();
+        run(
+            "EVERY 1 HOURS
+SET ZERO = 0
+SET BOTH = 1 EQ 2 AND 1 / ZERO EQ 1
+SET EITHER = 1 EQ 1 OR 1 / ZERO EQ 1
+IF NOT (1 EQ 2 AND 1 / ZERO EQ 1)
+  SET BRANCH = 1
+END",
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(context.variables["BOTH"], Variable::Bool(false));
+        assert_eq!(context.variables["EITHER"], Variable::Bool(true));
+        assert_eq!(context.variables["BRANCH"], Variable::Int(1));
+
+        // The right hand side is evaluated when it's needed.
+        let err = run(
+            "EVERY 1 HOURS
+SET ZERO = 0
+SET BOTH = 1 EQ 1 AND 1 / ZERO EQ 1",
+            &mut context,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("divides by zero"), "{}", err);
+    }
+
+    #[test]
+    fn procedures_cant_see_the_variables_of_their_callers() {
+        let mut context = context();
+        run(
+            "EVERY 1 HOURS
+SET NAME = \"global\"
+DEFINE INNER()
+  TAG \"inner\" NAME
+END
+DEFINE OUTER(NAME)
+  TAG \"outer\" NAME
+  CALL INNER()
+END
+CALL OUTER(\"param\")",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.tags, ["outer:param", "inner:global"]);
+        assert_eq!(context.variables["NAME"], text("global"));
+    }
+
+    #[test]
+    fn at_most_64_calls_can_be_nested() {
+        let source = |first| {
+            format!(
+                "EVERY 1 HOURS
+DEFINE DEEPER(DEPTH)
+  IF DEPTH LESSER 64
+    CALL DEEPER(DEPTH + 1)
+  ELSE
+    TAG \"depth\" DEPTH
+  END
+END
+CALL DEEPER({})",
+                first
+            )
+        };
+
+        let mut deepest = context();
+        run(&source(1), &mut deepest).unwrap();
+        assert_eq!(deepest.tags, ["depth:64"]);
+
+        let mut context = context();
+        let err = run(&source(0), &mut context).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Calling DEEPER exceeded the maximum depth of 64 nested calls, does it call itself \
+             endlessly?"
+        );
+        assert!(context.tags.is_empty());
+    }
+
+    #[test]
+    fn procedures_change_existing_globals() {
+        let path =
+            std::env::temp_dir().join(format!("timetrackrs-globals-{}.json", std::process::id()));
+        let mut context = context();
+        context.state = State::at(path.clone());
+        run(
+            "EVERY 1 HOURS
+PERSIST TOTAL = 0
+SET NAME = \"global\"
+DEFINE ADD(AMOUNT)
+  SET TOTAL = TOTAL + AMOUNT
+  SET AMOUNT = 0
+  SET LOCAL_ONLY = AMOUNT
+END
+DEFINE RENAME(NAME)
+  SET NAME = \"param\"
+END
+CALL ADD(2)
+CALL ADD(3)
+CALL RENAME(\"arg\")",
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(context.variables["TOTAL"], Variable::Int(5));
+        assert_eq!(context.variables["NAME"], text("global"));
+        assert!(!context.variables.contains_key("AMOUNT"));
+        assert!(!context.variables.contains_key("LOCAL_ONLY"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn placeholders_apply_their_format_spec() {
+        let mut context = context();
+        run(
+            r#"EVERY 1 HOURS
+SET MEMORY = 300
+SET NEGATIVE = -42
+SET CPU = 12.3456
+SET NAME = "chrome"
+TAG "right" "[{MEMORY:>8}]"
+TAG "left" "[{NAME:<8}]"
+TAG "center" "[{NAME:*^11}]"
+TAG "number" "[{MEMORY:6}]"
+TAG "text" "[{NAME:8}]"
+TAG "zeros" "{NEGATIVE:06}"
+TAG "decimals" "{CPU:.2}"
+TAG "cut" "{NAME:.3}"
+TAG "narrow" "{NAME:3}"
+TAG "time" "{NOW:%H:%M}"
+TAG "padded" "[{NOW:>8%H:%M}]"
+TAG "date" "{NOW:%d.%m.%Y}""#,
+            &mut context,
+        )
+        .unwrap();
+
+        let now = match &context.variables["NOW"] {
+            Variable::DateTime(now) => *now,
+            variable => panic!("NOW is {:?}", variable),
+        };
+        assert_eq!(
+            context.tags,
+            [
+                "right:[     300]".to_owned(),
+                "left:[chrome  ]".to_owned(),
+                "center:[**chrome***]".to_owned(),
+                "number:[   300]".to_owned(),
+                "text:[chrome  ]".to_owned(),
+                "zeros:-00042".to_owned(),
+                "decimals:12.35".to_owned(),
+                "cut:chr".to_owned(),
+                "narrow:chrome".to_owned(),
+                format!("time:{}", now.format("%H:%M")),
+                format!("padded:[   {}]", now.format("%H:%M")),
+                format!("date:{}", now.format("%d.%m.%Y")),
+            ]
+        );
     }
 }
@@ -0,0 +1,467 @@
+use super::{Diagnostic, ErrorCode, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// Keywords as well as variable names, e.g. `IF` or `TITLE`.
+    Ident(String),
+    /// A literal wrapped in `"`, `'` or `` ` ``, without the quotes.
+    Str(String),
+    Number(String),
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Dot,
+    Colon,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Newline,
+    Eof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TokenKind::*;
+        match self {
+            Ident(ident) => write!(f, "`{}`", ident),
+            Str(string) => write!(f, "\"{}\"", string),
+            Number(number) => write!(f, "`{}`", number),
+            LBracket => write!(f, "`[`"),
+            RBracket => write!(f, "`]`"),
+            LParen => write!(f, "`(`"),
+            RParen => write!(f, "`)`"),
+            LBrace => write!(f, "`{{`"),
+            RBrace => write!(f, "`}}`"),
+            Comma => write!(f, "`,`"),
+            Dot => write!(f, "`.`"),
+            Colon => write!(f, "`:`"),
+            Assign => write!(f, "`=`"),
+            Plus => write!(f, "`+`"),
+            Minus => write!(f, "`-`"),
+            Star => write!(f, "`*`"),
+            Slash => write!(f, "`/`"),
+            Percent => write!(f, "`%`"),
+            Newline => write!(f, "end of line"),
+            Eof => write!(f, "end of rule"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_next(&self) -> Option<char> {
+        self.chars.get(self.pos + 1).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut string = String::new();
+        while let Some(c) = self.peek() {
+            if !predicate(c) {
+                break;
+            }
+            string.push(c);
+            self.bump();
+        }
+        string
+    }
+}
+
+/// Splits a rule body into tokens. Consecutive line breaks are collapsed
+/// into a single `Newline` token and the stream always ends with `Eof`.
+/// Line breaks inside of `[]` and `()` don't end the statement, so long lists can be
+/// spread over several lines. Comments (`#`, `//` and `/* */`) are skipped.
+/// Characters that can't be tokenized are reported and skipped, so that the
+/// parser still gets to see the rest of the rule.
+pub fn tokenize(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        pos: 0,
+        line: 1,
+        column: 1,
+    };
+
+    let mut tokens: Vec<Token> = vec![];
+    let mut diagnostics = vec![];
+    // How many brackets, parentheses and braces are open.
+    let mut depth = 0usize;
+
+    while let Some(c) = lexer.peek() {
+        let (line, column) = (lexer.line, lexer.column);
+
+        let kind = match c {
+            '\n' => {
+                lexer.bump();
+                match tokens.last() {
+                    Some(token) if token.kind != TokenKind::Newline && depth == 0 => {
+                        TokenKind::Newline
+                    }
+                    _ => continue,
+                }
+            }
+            c if c.is_whitespace() => {
+                lexer.bump();
+                continue;
+            }
+            '#' => {
+                lexer.take_while(|ch| ch != '\n');
+                continue;
+            }
+            '/' if lexer.peek_next() == Some('/') => {
+                lexer.take_while(|ch| ch != '\n');
+                continue;
+            }
+            '/' if lexer.peek_next() == Some('*') => {
+                lexer.bump();
+                lexer.bump();
+                let mut multiline = false;
+                loop {
+                    match lexer.bump() {
+                        Some('*') if lexer.peek() == Some('/') => {
+                            lexer.bump();
+                            break;
+                        }
+                        Some('\n') => multiline = true,
+                        Some(_) => {}
+                        None => {
+                            diagnostics.push(Diagnostic::error(
+                                ErrorCode::UnterminatedComment,
+                                Span {
+                                    line,
+                                    column,
+                                    len: 2,
+                                },
+                                "Unterminated block comment",
+                            ));
+                            break;
+                        }
+                    }
+                }
+                // A comment spanning several lines still separates the statements around it.
+                match tokens.last() {
+                    Some(token) if multiline && token.kind != TokenKind::Newline && depth == 0 => {
+                        TokenKind::Newline
+                    }
+                    _ => continue,
+                }
+            }
+            '[' | '(' | '{' => {
+                lexer.bump();
+                depth += 1;
+                match c {
+                    '[' => TokenKind::LBracket,
+                    '(' => TokenKind::LParen,
+                    _ => TokenKind::LBrace,
+                }
+            }
+            ']' | ')' | '}' => {
+                lexer.bump();
+                depth = depth.saturating_sub(1);
+                match c {
+                    ']' => TokenKind::RBracket,
+                    ')' => TokenKind::RParen,
+                    _ => TokenKind::RBrace,
+                }
+            }
+            ',' | '.' | ':' | '=' | '+' | '-' | '*' | '/' | '%' => {
+                lexer.bump();
+                match c {
+                    ',' => TokenKind::Comma,
+                    '.' => TokenKind::Dot,
+                    ':' => TokenKind::Colon,
+                    '=' => TokenKind::Assign,
+                    '+' => TokenKind::Plus,
+                    '-' => TokenKind::Minus,
+                    '*' => TokenKind::Star,
+                    '/' => TokenKind::Slash,
+                    _ => TokenKind::Percent,
+                }
+            }
+            '"' | '\'' | '`' => {
+                lexer.bump();
+                let string = lexer.take_while(|ch| ch != c && ch != '\n');
+                if lexer.peek() == Some(c) {
+                    lexer.bump();
+                } else {
+                    diagnostics.push(Diagnostic::error(
+                        ErrorCode::UnterminatedString,
+                        Span {
+                            line,
+                            column,
+                            len: lexer.column - column,
+                        },
+                        "Unterminated string literal",
+                    ));
+                }
+                TokenKind::Str(string)
+            }
+            c if c.is_ascii_digit() => {
+                TokenKind::Number(lexer.take_while(|ch| ch.is_ascii_digit() || ch == '.'))
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                TokenKind::Ident(lexer.take_while(|ch| ch.is_alphanumeric() || ch == '_'))
+            }
+            c => {
+                lexer.bump();
+                diagnostics.push(Diagnostic::error(
+                    ErrorCode::UnexpectedCharacter,
+                    Span {
+                        line,
+                        column,
+                        len: 1,
+                    },
+                    format!("Unexpected character `{}`", c),
+                ));
+                continue;
+            }
+        };
+
+        let len = if kind == TokenKind::Newline {
+            1
+        } else {
+            lexer.column - column
+        };
+
+        tokens.push(Token {
+            kind,
+            span: Span { line, column, len },
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span {
+            line: lexer.line,
+            column: lexer.column,
+            len: 0,
+        },
+    });
+
+    (tokens, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenKind::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        let (tokens, diagnostics) = tokenize(source);
+        assert_eq!(diagnostics, []);
+        tokens.into_iter().map(|token| token.kind).collect()
+    }
+
+    fn ident(name: &str) -> TokenKind {
+        Ident(name.to_owned())
+    }
+
+    #[test]
+    fn tokenizes_statements() {
+        assert_eq!(
+            kinds("SET TOTAL = KEYSTROKES + 1.5 * (2 - 3) / 4 % 5"),
+            [
+                ident("SET"),
+                ident("TOTAL"),
+                Assign,
+                ident("KEYSTROKES"),
+                Plus,
+                Number("1.5".into()),
+                Star,
+                LParen,
+                Number("2".into()),
+                Minus,
+                Number("3".into()),
+                RParen,
+                Slash,
+                Number("4".into()),
+                Percent,
+                Number("5".into()),
+                Eof,
+            ]
+        );
+        assert_eq!(
+            kinds(r#"HTTP_POST 'url' { "a": [W.TITLE, `x`] }"#),
+            [
+                ident("HTTP_POST"),
+                Str("url".into()),
+                LBrace,
+                Str("a".into()),
+                Colon,
+                LBracket,
+                ident("W"),
+                Dot,
+                ident("TITLE"),
+                Comma,
+                Str("x".into()),
+                RBracket,
+                RBrace,
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_are_one_based_characters() {
+        let (tokens, _) = tokenize("AT 17:30\n  PRINT \"ä b\"");
+        let spans: Vec<_> = tokens
+            .iter()
+            .map(|token| (token.span.line, token.span.column, token.span.len))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (1, 1, 2),
+                (1, 4, 2),
+                (1, 6, 1),
+                (1, 7, 2),
+                (1, 9, 1),
+                (2, 3, 5),
+                (2, 9, 5),
+                (2, 14, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn collapses_line_breaks() {
+        assert_eq!(
+            kinds("\n\nGET_WINDOWS\n\n\nSAVE_TO_DB\n"),
+            [
+                ident("GET_WINDOWS"),
+                Newline,
+                ident("SAVE_TO_DB"),
+                Newline,
+                Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn line_breaks_inside_brackets_continue_the_statement() {
+        assert_eq!(
+            kinds("IN [\n  \"a\",\n  \"b\"\n]\nEND"),
+            [
+                ident("IN"),
+                LBracket,
+                Str("a".into()),
+                Comma,
+                Str("b".into()),
+                RBracket,
+                Newline,
+                ident("END"),
+                Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments() {
+        assert_eq!(
+            kinds("PRINT \"# not a comment\" # comment\nBREAK // comment\nCONTINUE"),
+            [
+                ident("PRINT"),
+                Str("# not a comment".into()),
+                Newline,
+                ident("BREAK"),
+                Newline,
+                ident("CONTINUE"),
+                Eof,
+            ]
+        );
+        assert_eq!(
+            kinds("BREAK /* a\nb */ CONTINUE /* c */ END"),
+            [
+                ident("BREAK"),
+                Newline,
+                ident("CONTINUE"),
+                ident("END"),
+                Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_and_skips_what_it_cant_tokenize() {
+        let (tokens, diagnostics) = tokenize("PRINT ; \"open\nEND /* open");
+        let kinds: Vec<_> = tokens.into_iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ident("PRINT"),
+                Str("open".into()),
+                Newline,
+                ident("END"),
+                Eof
+            ]
+        );
+
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.span))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    ErrorCode::UnexpectedCharacter,
+                    Span {
+                        line: 1,
+                        column: 7,
+                        len: 1
+                    }
+                ),
+                (
+                    ErrorCode::UnterminatedString,
+                    Span {
+                        line: 1,
+                        column: 9,
+                        len: 5
+                    }
+                ),
+                (
+                    ErrorCode::UnterminatedComment,
+                    Span {
+                        line: 2,
+                        column: 5,
+                        len: 2
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use super::{
+    parser::parse_library, types::Type, Diagnostic, ErrorCode, Procedure, Severity, Span, Stmt,
+};
+use rustc_hash::FxHashMap;
+use std::sync::Arc;
+
+/// A rule that's meant to be pulled into other rules with `IMPORT`.
+#[derive(Debug)]
+pub struct Library {
+    /// Its own procedures as well as the ones it imported itself.
+    pub procedures: FxHashMap<String, Arc<Procedure>>,
+    /// The top level `SET` statements, they run wherever the library is imported.
+    pub constants: Vec<Stmt>,
+    /// The types of the variables the constants set, known to the rules that import it.
+    pub(super) types: FxHashMap<String, Type>,
+}
+
+/// The library rules of the user by name. Every library is parsed only once, the first
+/// time it gets imported, after that the cached result is handed out.
+#[derive(Debug, Default)]
+pub struct Libraries {
+    sources: FxHashMap<String, String>,
+    cache: FxHashMap<String, Result<Arc<Library>, Diagnostic>>,
+    /// The libraries that are being parsed right now, the last one is the innermost
+    /// `IMPORT`. Used to detect cycles.
+    resolving: Vec<String>,
+}
+
+impl Libraries {
+    pub fn new(sources: FxHashMap<String, String>) -> Self {
+        Self {
+            sources,
+            ..Self::default()
+        }
+    }
+
+    /// Parses the library `name` or takes it from the cache. The span of the returned
+    /// diagnostic is left empty, it's up to the caller to point it at the `IMPORT`.
+    pub fn resolve(&mut self, name: &str) -> Result<Arc<Library>, Diagnostic> {
+        if let Some(result) = self.cache.get(name) {
+            return result.clone();
+        }
+
+        if let Some(start) = self.resolving.iter().position(|library| library == name) {
+            let mut cycle = self.resolving[start..].to_vec();
+            cycle.push(name.to_owned());
+            return Err(Diagnostic::error(
+                ErrorCode::ImportCycle,
+                Span::default(),
+                format!("IMPORT cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+
+        let source = match self.sources.get(name) {
+            Some(source) => source.clone(),
+            None => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnknownLibrary,
+                    Span::default(),
+                    format!("The library `{}` doesn't exist", name),
+                ))
+            }
+        };
+
+        self.resolving.push(name.to_owned());
+        let (library, diagnostics) = parse_library(&source, self);
+        self.resolving.pop();
+
+        let result = library.map(Arc::new).ok_or_else(|| {
+            let mut diagnostic = Diagnostic::error(
+                ErrorCode::InvalidLibrary,
+                Span::default(),
+                format!("The library `{}` can't be imported", name),
+            );
+
+            // Only the first error is shown, it's the root cause if libraries import
+            // each other.
+            let cause = diagnostics
+                .iter()
+                .find(|diagnostic| diagnostic.severity == Severity::Error);
+            diagnostic.help = match cause {
+                Some(cause) if cause.code == ErrorCode::InvalidLibrary => cause.help.clone(),
+                Some(cause) => Some(format!(
+                    "`{}` line {}, column {}: {}",
+                    name, cause.span.line, cause.span.column, cause.message
+                )),
+                None => None,
+            };
+
+            diagnostic
+        });
+
+        self.cache.insert(name.to_owned(), result.clone());
+
+        result
+    }
+}
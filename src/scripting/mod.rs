@@ -1,7 +1,26 @@
+mod ast;
+mod context;
+mod diagnostic;
+mod functions;
 mod interpreter;
-/// Parse -> Interpret instructions -> Pass the instructions into an execution thread -> Execute
-/// instructions
+mod lexer;
+mod library;
+/// Tokenize -> Parse the tokens into a `Program` -> Pass the `Program` to the `Scheduler`
+/// -> Walk the statements on every tick
 mod parser;
+mod schedule;
+mod scheduler;
+mod state;
+mod types;
 
+pub use ast::*;
+pub use context::*;
+pub use diagnostic::*;
+pub use functions::*;
 pub use interpreter::*;
+pub use lexer::*;
+pub use library::*;
 pub use parser::*;
+pub use schedule::*;
+pub use scheduler::*;
+pub use types::*;
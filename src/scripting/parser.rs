@@ -1,1043 +1,2639 @@
-use super::{Conditional, Executable, Instruction, Iterative, Rule, Variable, VariableMapType};
-use crate::{
-    capture::{
-        create_capturer,
-        pc_common::{get_network_ssid, Event, Window, KEYSTROKES, MOUSE_CLICKS},
-    },
-    graphql::{get_or_insert_user_ssid, send_user_event},
-    rest_api::send_screenshots,
-    scripting::ConditionalFn,
+use super::{
+    builtin_type, find_project, tokenize, window_field_type, Aggregate, Align, BinaryOp, Branch,
+    CompareOp, Cron, Days, Diagnostic, ErrorCode, Expr, FormatSpec, Function, Handler, Json,
+    Libraries, Library, Procedure, Program, Reset, Schedule, Screens, Segment, Selection, Severity,
+    Span, Stmt, Sustained, Token, TokenKind, Trigger, Type,
 };
 
+use crate::{graphql::Project, notification::Urgency};
+use chrono::{
+    format::{Item, StrftimeItems},
+    Duration as ChronoDuration, NaiveTime, Weekday,
+};
 use regex::Regex;
-use serde_json::Value;
-use std::{convert::TryInto, sync::atomic::Ordering, time::Duration};
-
-/// SAFETY: The use of a raw pointer (`*mut VariableMapType`) is safe as long as
-/// you ensure that the value won't get dropped before the execution finishes,
-/// which will be the case as long as you execute everything in a sequential order,
-/// but if you try to do anything concurrently you should switch to
-/// `Arc<ReadWrite<VariableMapType>>`, because that'll ensure there are no
-/// data races and undefined behaviour in the case of mutable borrow of a the value.
-/// Right now there's no chance of this causing undefined behaviour, because the
-/// current execution model does not include concurrency and all executables are executed
-/// one after another.
-
-/// Returns the duration to wait for execution and a vector of executable pointers.
-pub fn parse(
+use rustc_hash::FxHashMap;
+use std::{
+    collections::hash_map::Entry,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Words that have a meaning of their own and therefore can't be used as variable names.
+const KEYWORDS: &[&str] = &[
+    "EVERY",
+    "AT",
+    "CRON",
+    "ON",
+    "IF",
+    "ELSEIF",
+    "ELSE",
+    "END",
+    "ITERATE",
+    "DEFINE",
+    "CALL",
+    "IMPORT",
+    "SET",
+    "PRINT",
+    "LOG",
+    "TAG",
+    "SET_META",
+    "ASSIGN_PROJECT",
+    "NOTIFY",
+    "RUN",
+    "HTTP_POST",
+    "PERSIST",
+    "SAVE_TO_DB",
+    "GET_NETWORK_SSID",
+    "GET_PERIPHERALS",
+    "GET_WINDOWS",
+    "CAPTURE_SCREEN",
+    "NOT",
+    "AND",
+    "OR",
+    "EQ",
+    "BIGGER",
+    "LESSER",
+    "IN",
+    "MATCH",
+    "FOR",
+    "WHERE",
+    "BREAK",
+    "CONTINUE",
+];
+
+/// Only functions if they're followed by `(`, so they still work as variable names.
+const AGGREGATES: &[&str] = &["COUNT", "ANY", "ALL", "FIRST", "SUM"];
+
+/// Hands out the ids of `IF ... FOR` conditions, they're unique across all parsed rules
+/// and libraries.
+static NEXT_SUSTAINED_ID: AtomicUsize = AtomicUsize::new(0);
+
+const TIME_UNITS: &[&str] = &["MILLISECONDS", "SECONDS", "MINUTES", "HOURS"];
+
+const TIME_UNITS_HELP: &str = "Your options are: MILLISECONDS, SECONDS, MINUTES and HOURS.";
+
+const TRIGGERS_HELP: &str =
+    "Your options are: WINDOW_CHANGE, IDLE_START, IDLE_END, NETWORK_CHANGE and RESUME.";
+
+const WINDOW_FIELDS_HELP: &str = "Windows have TITLE, PROCESS_NAME, CMD, EXE, CWD, MEMORY, STATUS, START_TIME, CPU_USAGE and UPTIME_OF_PROCESS.";
+
+const FUNCTIONS_HELP: &str = "Your options are: LOWER, UPPER, TRIM, CONTAINS, STARTS_WITH, ENDS_WITH, REPLACE, SPLIT, SUBSTRING, LEN, BASENAME, DIRNAME and URL_HOST.";
+
+const CRON_HELP: &str = "the fields are minute, hour, day of the month, month and day of the week, e.g. `CRON \"*/5 9-17 * * 1-5\"`";
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+/// Parses a rule body into a `Program`.
+///
+/// Parsing doesn't stop at the first problem, every error and warning in the body is
+/// returned. The `Program` is only `None` if at least one of them is an error.
+pub fn parse(string: impl AsRef<str>) -> (Option<Program>, Vec<Diagnostic>) {
+    parse_with_libraries(string, &mut Libraries::default())
+}
+
+/// Same as `parse`, but `IMPORT` statements are resolved with `libraries`.
+pub fn parse_with_libraries(
     string: impl AsRef<str>,
-    variable_map: *mut VariableMapType,
-) -> anyhow::Result<(Duration, Vec<Box<dyn Executable>>)> {
-    let mut executables = vec![];
+    libraries: &mut Libraries,
+) -> (Option<Program>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(string.as_ref(), libraries, None, false);
+    let program = parser.parse_program();
+    parser.finish(program)
+}
 
-    let mut timeout: Option<Duration> = None;
+/// Same as `parse_with_libraries`, but the quoted project names of `ASSIGN_PROJECT` are
+/// checked against `projects`.
+pub fn parse_with_projects(
+    string: impl AsRef<str>,
+    libraries: &mut Libraries,
+    projects: &[Project],
+) -> (Option<Program>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(string.as_ref(), libraries, Some(projects), false);
+    let program = parser.parse_program();
+    parser.finish(program)
+}
 
-    let string = string.as_ref().replace("\t", " ");
+/// Parses the body of a library rule, which has no schedule and may only contain `DEFINE`,
+/// `SET` and `IMPORT` statements at the top level.
+pub(super) fn parse_library(
+    string: &str,
+    libraries: &mut Libraries,
+) -> (Option<Library>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(string, libraries, None, true);
+    let (_, _, constants) = parser.parse_top_level();
+    let library = Library {
+        procedures: std::mem::take(&mut parser.procedures),
+        constants,
+        types: std::mem::take(&mut parser.types),
+    };
+    parser.finish(Some(library))
+}
 
-    let lines_split = string.split("\n");
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    diagnostics: Vec<Diagnostic>,
+    libraries: &'a mut Libraries,
+    /// The projects of the user, if they're known.
+    projects: Option<&'a [Project]>,
+    /// Whether a library rule is being parsed.
+    library: bool,
+    procedures: FxHashMap<String, Arc<Procedure>>,
+    /// The types of the global variables created with `SET`.
+    types: FxHashMap<String, Type>,
+    /// The types of the local variables of the procedure that's being parsed.
+    locals: Option<FxHashMap<String, Type>>,
+    /// Every `CALL` with its number of arguments, checked once all procedures are known,
+    /// since a procedure may be called before it's defined.
+    calls: Vec<(String, Span, usize)>,
+    /// The names given to the elements with `ITERATE <name> IN`, the last one belongs to the
+    /// innermost loop.
+    bindings: Vec<(String, Type)>,
+    /// How many `ITERATE` blocks the current statement is in.
+    loops: usize,
+}
 
-    let mut lines: Vec<Vec<&str>> = vec![];
+fn unexpected(token: &Token, expected: &str) -> Diagnostic {
+    Diagnostic::error(
+        ErrorCode::UnexpectedToken,
+        token.span,
+        format!("Expected {}, found {}", expected, token.kind),
+    )
+}
 
-    for line in lines_split {
-        if line.is_empty() {
-            continue;
+impl<'a> Parser<'a> {
+    fn new(
+        source: &str,
+        libraries: &'a mut Libraries,
+        projects: Option<&'a [Project]>,
+        library: bool,
+    ) -> Self {
+        let (tokens, diagnostics) = tokenize(source);
+        Self {
+            tokens,
+            pos: 0,
+            diagnostics,
+            libraries,
+            projects,
+            library,
+            procedures: FxHashMap::default(),
+            types: FxHashMap::default(),
+            locals: None,
+            calls: vec![],
+            bindings: vec![],
+            loops: 0,
         }
+    }
 
-        let word_positions = get_word_positions(line);
-
-        let mut words: Vec<&str> = vec![];
+    /// Sorts the diagnostics and drops `result` if any of them is an error.
+    fn finish<T>(mut self, result: Option<T>) -> (Option<T>, Vec<Diagnostic>) {
+        self.diagnostics
+            .sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
 
-        for (start, end) in word_positions {
-            words.push(&line[start..end]);
-        }
+        let failed = self
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error);
 
-        lines.push(words);
+        (result.filter(|_| !failed), self.diagnostics)
     }
 
-    let mut line_pos = 0;
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
 
-    while line_pos < lines.len() {
-        let line = &lines[line_pos];
+    /// The token after the current one, `Eof` at the end.
+    fn peek_next(&self) -> &Token {
+        self.tokens
+            .get(self.pos + 1)
+            .unwrap_or(&self.tokens[self.tokens.len() - 1])
+    }
 
-        let word = line[0];
+    /// Returns the current token and moves on to the next one, `Eof` is never skipped.
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
 
-        match word {
-            "EVERY" => {
-                if line.get(1).is_none() {
-                    anyhow::bail!(
-                        "You haven't provided a number as second parameter at line {}",
-                        line_pos
-                    );
-                } else if line.get(2).is_none() {
-                    anyhow::bail!("You haven't provided a valid time variant at line {}.\nYour options are: MILLISECONDS, SECONDS, MINUTES and HOURS.", line_pos);
-                }
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(ident) if ident == keyword)
+    }
 
-                let amount: u64 = match line[1].parse() {
-                    Ok(amount) => amount,
-                    Err(_) => {
-                        anyhow::bail!(
-                            "You haven't provided a valid number as a second parameter at line {}.",
-                            line_pos
-                        )
-                    }
-                };
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let is_keyword = self.is_keyword(keyword);
+        if is_keyword {
+            self.advance();
+        }
+        is_keyword
+    }
 
-                let time_variant = line[2];
+    fn expect(&mut self, kind: TokenKind) -> ParseResult<Token> {
+        let token = self.advance();
+        if token.kind != kind {
+            return Err(unexpected(&token, &kind.to_string()));
+        }
+        Ok(token)
+    }
 
-                let time = match time_variant {
-                   "MILLISECONDS" => amount,
-                   "SECONDS" => amount * 1000,
-                   "MINUTES" => amount * 1000 * 60,
-                   "HOURS" => amount * 1000 * 60 * 60,
-                   _ => anyhow::bail!("The time variant {} you provided at line {} doesn't exist.\nYour options are: MILLISECONDS, SECONDS, MINUTES and HOURS.", time_variant, line_pos)
-                };
+    fn skip_newlines(&mut self) {
+        while self.peek().kind == TokenKind::Newline {
+            self.advance();
+        }
+    }
 
-                timeout = Some(Duration::from_millis(time));
-            }
-            "IF" => {
-                executables.push(parse_conditional(&lines, &mut line_pos, variable_map)?.into());
-            }
-            "ITERATE" => {
-                executables.push(parse_iterator(&lines, &mut line_pos, variable_map)?.into());
-            }
-            _ => {
-                if let Some(instruction) = parse_instruction(&line, variable_map)? {
-                    executables.push(instruction.into());
-                }
+    /// Every statement has to be followed by a line break or the end of the rule.
+    fn expect_end_of_statement(&mut self) -> ParseResult<()> {
+        match self.peek().kind {
+            TokenKind::Newline => {
+                self.advance();
+                Ok(())
             }
-        };
-
-        line_pos += 1;
+            TokenKind::Eof => Ok(()),
+            _ => Err(unexpected(self.peek(), "end of line")),
+        }
     }
 
-    match timeout {
-        Some(timeout) => Ok((timeout, executables)),
-        None => anyhow::bail!("You haven't specified the EVERY statement."),
+    /// Records `diagnostic` and skips the rest of the line, so that parsing can go on with
+    /// the next statement.
+    fn recover(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+        // If the line break itself was unexpected it has already been consumed, stepping
+        // back keeps the next line from being skipped as well.
+        if self.pos > 0 && self.tokens[self.pos - 1].kind == TokenKind::Newline {
+            self.pos -= 1;
+        }
+        while !matches!(self.peek().kind, TokenKind::Newline | TokenKind::Eof) {
+            self.advance();
+        }
     }
-}
 
-fn parse_instruction(
-    line: &[&str],
-    variable_map: *mut VariableMapType,
-) -> anyhow::Result<Option<Instruction>> {
-    let word = line[0];
-    match word {
-        "PRINT" => {
-            debug!("Parsing PRINT Statement");
-            let word = line[1];
-            let is_string = is_string(word);
-
-            let word = if is_string {
-                word[1..word.len() - 1].to_owned()
-            } else {
-                word.to_owned()
-            };
+    fn reported(&self, code: ErrorCode) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.code == code)
+    }
 
-            let function: Instruction = if is_string {
-                Box::new(move || {
-                    println!("{}", word);
-                    Ok(())
-                })
-                .into()
-            } else {
-                Box::new(move || {
-                    let map = unsafe { &*variable_map };
+    /// Parses the remainder of a block header such as `IF ...` including the line break.
+    /// On failure the problem is recorded and `fallback` is returned instead, that way the
+    /// body and the closing `END` of the block are still parsed as such.
+    fn parse_header<T>(
+        &mut self,
+        fallback: T,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> T {
+        let result = parse(self).and_then(|header| {
+            self.expect_end_of_statement()?;
+            Ok(header)
+        });
+        match result {
+            Ok(header) => header,
+            Err(diagnostic) => {
+                self.recover(diagnostic);
+                fallback
+            }
+        }
+    }
 
-                    let variable = match map.get(word.as_str()) {
-                        Some(variable) => variable,
-                        None => anyhow::bail!("Couldn't find the Variable with Key {}", word),
-                    };
+    fn parse_program(&mut self) -> Option<Program> {
+        let (schedules, handlers, body) = self.parse_top_level();
+
+        if schedules.is_empty()
+            && handlers.is_empty()
+            && !self.reported(ErrorCode::InvalidEvery)
+            && !self.reported(ErrorCode::InvalidSchedule)
+            && !self.reported(ErrorCode::UnknownTrigger)
+        {
+            self.diagnostics.push(
+                Diagnostic::error(
+                    ErrorCode::MissingEvery,
+                    Span {
+                        line: 1,
+                        column: 1,
+                        len: 0,
+                    },
+                    "You haven't specified when the rule runs",
+                )
+                .with_help(
+                    "start the rule with e.g. `EVERY 5 SECONDS` or `AT 17:30`, or add an `ON` block",
+                ),
+            );
+            return None;
+        }
 
-                    println!("{}", variable);
+        Some(Program {
+            schedules,
+            procedures: std::mem::take(&mut self.procedures),
+            handlers,
+            body,
+        })
+    }
 
-                    Ok(())
-                })
-                .into()
-            };
-            Ok(Some(function.into()))
-        }
-        "SAVE_TO_DB" => {
-            let function = move || {
-                debug!("Saving to DB");
+    /// Parses the whole rule, schedules, `ON`, `DEFINE`, `IMPORT` and `PERSIST` are only
+    /// allowed here.
+    fn parse_top_level(&mut self) -> (Vec<Schedule>, Vec<Handler>, Vec<Stmt>) {
+        let mut schedules = vec![];
+        let mut handlers = vec![];
+        let mut body = vec![];
 
-                let map = unsafe { &mut *variable_map };
+        loop {
+            self.skip_newlines();
 
-                let rule_id = match map.get("RULE_ID") {
-                    Some(Variable::RcStr(string)) => (**string).clone(),
-                    _ => anyhow::bail!("RULE_ID is not a String"),
-                };
+            if self.peek().kind == TokenKind::Eof {
+                break;
+            }
 
-                let rule_body = match map.get("RULE_BODY") {
-                    Some(Variable::RcStr(string)) => (**string).clone(),
-                    _ => anyhow::bail!("RULE_BODY is not a String"),
-                };
+            if ["EVERY", "AT", "CRON"]
+                .iter()
+                .any(|keyword| self.is_keyword(keyword))
+            {
+                let token = self.advance();
+                if self.library {
+                    self.recover(Diagnostic::error(
+                        ErrorCode::UnexpectedToken,
+                        token.span,
+                        format!(
+                            "Libraries are never executed on their own, {} can't be used in them",
+                            token.kind
+                        ),
+                    ));
+                    continue;
+                }
+                let result = self.parse_schedule(&token).and_then(|schedule| {
+                    self.expect_end_of_statement()?;
+                    Ok(schedule)
+                });
+                match result {
+                    Ok(schedule) => schedules.push(schedule),
+                    Err(diagnostic) => self.recover(diagnostic),
+                }
+                continue;
+            }
 
-                let seconds_since_last_input = match map.get("SECONDS_SINCE_LAST_INPUT") {
-                    Some(Variable::U64(int)) => *int,
-                    Some(s) => {
-                        dbg!(s);
-                        anyhow::bail!("SECONDS_SINCE_LAST_INPUT is not a U64")
-                    }
-                    None => anyhow::bail!("SECONDS_SINCE_LAST_INPUT is not a U64"),
-                };
+            if self.is_keyword("ON") {
+                let token = self.advance();
+                if self.library {
+                    // Still parsed as a block, otherwise its END would be unmatched.
+                    self.diagnostics.push(Diagnostic::error(
+                        ErrorCode::UnexpectedToken,
+                        token.span,
+                        "Libraries are never executed on their own, ON can't be used in them",
+                    ));
+                }
+                if let Some(handler) = self.parse_on(&token) {
+                    handlers.push(handler);
+                }
+                continue;
+            }
 
-                let windows: Vec<Window> = match map.get("WINDOWS") {
-                    Some(Variable::Vector(vec)) => {
-                        let mut windows = vec![];
-                        for variable in vec.iter() {
-                            let map = match variable {
-                                Variable::Map(map) => map,
-                                _ => anyhow::bail!("Variable is not a Map"),
-                            };
-                            windows.push((&**map).try_into()?);
+            if self.is_keyword("DEFINE") {
+                let token = self.advance();
+                if let Some((name, span, procedure)) = self.parse_define(&token) {
+                    match self.procedures.entry(name) {
+                        Entry::Occupied(entry) => self.diagnostics.push(Diagnostic::error(
+                            ErrorCode::DuplicateProcedure,
+                            span,
+                            format!("The procedure `{}` is defined more than once", entry.key()),
+                        )),
+                        Entry::Vacant(entry) => {
+                            entry.insert(Arc::new(procedure));
                         }
-                        windows
                     }
-                    _ => anyhow::bail!("WINDOWS is not a Vector"),
-                };
+                }
+                continue;
+            }
 
-                let screenshots: Option<Box<Vec<Value>>> = match map.get("SCREENSHOTS") {
-                    Some(Variable::SerdeJsonVector(value)) => Some(value.clone()),
-                    _ => None,
-                };
+            if self.is_keyword("IMPORT") {
+                self.advance();
+                let result = self.parse_import().and_then(|constants| {
+                    self.expect_end_of_statement()?;
+                    Ok(constants)
+                });
+                match result {
+                    Ok(constants) => body.extend(constants),
+                    Err(diagnostic) => self.recover(diagnostic),
+                }
+                continue;
+            }
 
-                let network: Option<String> = match map.get("NETWORK_SSID") {
-                    Some(Variable::RcStr(string)) => {
-                        match get_or_insert_user_ssid(string.as_str()) {
-                            Ok(id) => Some(id),
-                            Err(err) => {
-                                error!("{}", err);
-                                None
-                            }
-                        }
-                    }
-                    _ => None,
-                };
+            if self.is_keyword("PERSIST") {
+                let token = self.advance();
+                if self.library {
+                    self.recover(Diagnostic::error(
+                        ErrorCode::UnexpectedToken,
+                        token.span,
+                        "Libraries have no state of their own, PERSIST can't be used in them",
+                    ));
+                    continue;
+                }
+                let result = self.parse_persist().and_then(|stmt| {
+                    self.expect_end_of_statement()?;
+                    Ok(stmt)
+                });
+                match result {
+                    Ok(stmt) => body.push(stmt),
+                    Err(diagnostic) => self.recover(diagnostic),
+                }
+                continue;
+            }
 
-                let event = Event {
-                    windows,
-                    rule: Some(Rule {
-                        id: rule_id,
-                        body: rule_body,
-                    }),
-                    network,
-                    screenshots,
-                    keyboard: KEYSTROKES.load(Ordering::Relaxed),
-                    mouse: MOUSE_CLICKS.load(Ordering::Relaxed),
-                    seconds_since_last_input,
-                };
+            let token = self.peek().clone();
+            match self.parse_statement() {
+                Ok(stmt @ Stmt::Set { .. }) => body.push(stmt),
+                Ok(_) if self.library => self.diagnostics.push(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    token.span,
+                    format!(
+                        "{} can't be used at the top level of a library, only DEFINE, SET and IMPORT can",
+                        token.kind
+                    ),
+                )),
+                Ok(stmt) => body.push(stmt),
+                Err(diagnostic) => self.recover(diagnostic),
+            }
+        }
 
-                KEYSTROKES.store(0, Ordering::SeqCst);
-                MOUSE_CLICKS.store(0, Ordering::SeqCst);
+        self.check_calls();
 
-                send_user_event(event)?;
+        (schedules, handlers, body)
+    }
 
-                if let Some(Variable::SerdeJsonVector(screenshots)) = map.get_mut("SCREENSHOTS") {
-                    // Clear the screenshots, so that we don't repeat them on the next save.
-                    screenshots.clear();
-                }
+    /// `ON <trigger>` followed by a block, `opener` is the `ON`.
+    fn parse_on(&mut self, opener: &Token) -> Option<Handler> {
+        let trigger = self.parse_header(None, |parser| {
+            let token = parser.advance();
+            let name = match &token.kind {
+                TokenKind::Ident(name) => name,
+                _ => return Err(unexpected(&token, "a trigger").with_help(TRIGGERS_HELP)),
+            };
+            match Trigger::from_name(name) {
+                Some(trigger) => Ok(Some(trigger)),
+                None => Err(Diagnostic::error(
+                    ErrorCode::UnknownTrigger,
+                    token.span,
+                    format!("The trigger `{}` doesn't exist", name),
+                )
+                .with_help(TRIGGERS_HELP)),
+            }
+        });
 
-                Ok(())
+        let (body, terminator) = self.parse_block(opener, &["END"]);
+        self.end_block(&terminator);
+
+        Some(Handler {
+            trigger: trigger?,
+            body,
+        })
+    }
+
+    /// `PERSIST NAME = <default> [RESET DAILY|WEEKLY]`, the default also gives the
+    /// variable its type.
+    fn parse_persist(&mut self) -> ParseResult<Stmt> {
+        let name = self.advance();
+        let name = match name.kind {
+            TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => name,
+            _ => return Err(unexpected(&name, "a variable name")),
+        };
+        self.expect(TokenKind::Assign)?;
+        let default = self.parse_expr()?;
+        self.declare(&name, self.type_of(&default));
+
+        let reset = if self.eat_keyword("RESET") {
+            let token = self.advance();
+            let reset = match &token.kind {
+                TokenKind::Ident(name) if name == "DAILY" => Some(Reset::Daily),
+                TokenKind::Ident(name) if name == "WEEKLY" => Some(Reset::Weekly),
+                _ => None,
             };
-            Ok(Some(function.into()))
-        }
-        "GET_NETWORK_SSID" => {
-            let function = move || {
-                let map = unsafe { &mut *variable_map };
-                if let Some(ssid) = get_network_ssid() {
-                    map.insert("NETWORK_SSID", ssid.into());
+            Some(reset.ok_or_else(|| {
+                unexpected(&token, "when to reset").with_help("Your options are: DAILY and WEEKLY.")
+            })?)
+        } else {
+            None
+        };
+
+        Ok(Stmt::Persist {
+            name,
+            default,
+            reset,
+        })
+    }
+
+    /// Resolves the library named after `IMPORT` and adds its procedures and the types of
+    /// its constants. Returns the constants, which run at the position of the `IMPORT`.
+    fn parse_import(&mut self) -> ParseResult<Vec<Stmt>> {
+        let token = self.advance();
+        let name = match &token.kind {
+            TokenKind::Str(name) => name.clone(),
+            _ => {
+                return Err(unexpected(&token, "a quoted library name")
+                    .with_help("e.g. `IMPORT \"common/idle-check\"`"))
+            }
+        };
+
+        let library = self
+            .libraries
+            .resolve(&name)
+            .map_err(|diagnostic| Diagnostic {
+                span: token.span,
+                ..diagnostic
+            })?;
+
+        for (procedure_name, procedure) in &library.procedures {
+            match self.procedures.entry(procedure_name.clone()) {
+                // The same library imported twice, e.g. through two other libraries.
+                Entry::Occupied(entry) if Arc::ptr_eq(entry.get(), procedure) => {}
+                Entry::Occupied(_) => {
+                    return Err(Diagnostic::error(
+                        ErrorCode::DuplicateProcedure,
+                        token.span,
+                        format!(
+                            "The procedure `{}` of `{}` is already defined",
+                            procedure_name, name
+                        ),
+                    ))
                 }
-                Ok(())
-            };
-            Ok(Some(function.into()))
-        }
-        "GET_PERIPHERALS" => {
-            let function = move || {
-                let map = unsafe { &mut *variable_map };
-                map.insert("KEYSTROKES", KEYSTROKES.load(Ordering::Relaxed).into());
-                map.insert("MOUSE_CLICKS", MOUSE_CLICKS.load(Ordering::Relaxed).into());
-                Ok(())
-            };
-            Ok(Some(function.into()))
+                Entry::Vacant(entry) => {
+                    entry.insert(procedure.clone());
+                }
+            }
         }
-        "GET_WINDOWS" => {
-            let mut capturer = create_capturer();
-            let function = move || {
-                let map = unsafe { &mut *variable_map };
-                let event = capturer.capture()?;
-                map.insert(
-                    "WINDOWS",
-                    event
-                        .windows
-                        .into_iter()
-                        .map(|w| w.into())
-                        .collect::<Vec<VariableMapType>>()
-                        .into(),
-                );
-                map.insert(
-                    "SECONDS_SINCE_LAST_INPUT",
-                    event.seconds_since_last_input.into(),
-                );
-                Ok(())
-            };
-            Ok(Some(function.into()))
+
+        for (constant, ty) in &library.types {
+            self.declare(constant, ty.clone());
         }
-        "CAPTURE_SCREEN" => {
-            use captis::*;
-
-            let variable = line
-                .get(1)
-                .map(|s| &s[1..s.len() - 1])
-                .ok_or_else(|| anyhow!("You haven't provided the screen number to capture"))?;
-
-            let capturer =
-                init_capturer().or_else(|_| Err(anyhow!("Couldn't initiate Screen capturer")))?;
-
-            return match variable {
-                "ALL" => {
-                    unsafe {
-                        (&mut *variable_map)
-                            .insert("SCREENSHOTS", Variable::SerdeJsonVector(Box::new(vec![])));
-                    }
-                    let function = move || {
-                        let map = unsafe { &mut *variable_map };
 
-                        let images = capturer.capture_all()?;
+        Ok(library.constants.clone())
+    }
 
-                        let mut file_vec = send_screenshots(&images)?;
+    /// Parses everything up to and including the line of the closing `END`. Returns `None`
+    /// if the header couldn't be parsed, the body is skipped nonetheless.
+    fn parse_define(&mut self, opener: &Token) -> Option<(String, Span, Procedure)> {
+        let header = self.parse_header(None, |parser| {
+            let token = parser.advance();
+            let name = match token.kind {
+                TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => name,
+                _ => {
+                    return Err(unexpected(&token, "a procedure name")
+                        .with_help("e.g. `DEFINE SAVE_IF_ACTIVE(LIMIT)`"))
+                }
+            };
 
-                        let vec = match map
-                            .get_mut("SCREENSHOTS")
-                            .ok_or_else(|| anyhow!("Couldn't get SCREENSHOTS from VariableMap"))?
-                        {
-                            Variable::SerdeJsonVector(vec) => vec,
-                            _ => anyhow::bail!("Variable is not a SerdeJsonVector"),
-                        };
+            parser.expect(TokenKind::LParen)?;
+            let mut params = vec![];
+            if parser.peek().kind == TokenKind::RParen {
+                parser.advance();
+            } else {
+                loop {
+                    let param = parser.advance();
+                    match param.kind {
+                        TokenKind::Ident(param) if !KEYWORDS.contains(&param.as_str()) => {
+                            params.push(param)
+                        }
+                        _ => return Err(unexpected(&param, "a parameter name")),
+                    }
+                    if parser.expect_argument_separator()? {
+                        break;
+                    }
+                }
+            }
 
-                        vec.append(&mut file_vec);
+            Ok(Some((name, token.span, params)))
+        });
 
-                        Ok(())
-                    };
-                    Ok(Some(function.into()))
-                }
-                "PRIMARY" => {
-                    unsafe {
-                        (&mut *variable_map)
-                            .insert("SCREENSHOTS", Variable::SerdeJsonVector(Box::new(vec![])));
-                    }
-                    let function = move || {
-                        let map = unsafe { &mut *variable_map };
+        // The parameters are only known once the procedure gets called.
+        let params = header.iter().flat_map(|(_, _, params)| params);
+        let locals = params.map(|param| (param.clone(), Type::Any)).collect();
+        let outer = self.locals.replace(locals);
+        let outer_bindings = std::mem::take(&mut self.bindings);
+        let outer_loops = std::mem::take(&mut self.loops);
 
-                        let image = capturer.capture_primary()?;
+        let (body, terminator) = self.parse_block(opener, &["END"]);
 
-                        let mut file_vec = send_screenshots(&[image])?;
+        self.locals = outer;
+        self.bindings = outer_bindings;
+        self.loops = outer_loops;
 
-                        let vec = match map
-                            .get_mut("SCREENSHOTS")
-                            .ok_or_else(|| anyhow!("Couldn't get SCREENSHOTS from VariableMap"))?
-                        {
-                            Variable::SerdeJsonVector(vec) => vec,
-                            _ => anyhow::bail!("Variable is not a SerdeJsonVector"),
-                        };
+        self.end_block(&terminator);
 
-                        vec.append(&mut file_vec);
+        let (name, span, params) = header?;
+        Some((name, span, Procedure { params, body }))
+    }
 
-                        Ok(())
-                    };
+    fn check_calls(&mut self) {
+        for (name, span, args) in std::mem::take(&mut self.calls) {
+            match self.procedures.get(&name) {
+                None => self.diagnostics.push(Diagnostic::error(
+                    ErrorCode::UnknownProcedure,
+                    span,
+                    format!("The procedure `{}` isn't defined", name),
+                )),
+                Some(procedure) if procedure.params.len() != args => {
+                    self.diagnostics.push(Diagnostic::error(
+                        ErrorCode::ArgumentCount,
+                        span,
+                        format!(
+                            "The procedure `{}` takes {} argument(s), but {} were given",
+                            name,
+                            procedure.params.len(),
+                            args
+                        ),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
 
-                    Ok(Some(function.into()))
+    /// `EVERY <duration> [BETWEEN <time> AND <time>] [ON <days>]`, `AT <time> [ON <days>]` or
+    /// `CRON "<expression>"`, `keyword` has already been consumed.
+    fn parse_schedule(&mut self, keyword: &Token) -> ParseResult<Schedule> {
+        match &keyword.kind {
+            TokenKind::Ident(ident) if ident == "EVERY" => {
+                let span = self.peek().span;
+                let interval = self.parse_duration("EVERY")?;
+                // The rule would run over and over without a pause.
+                if interval.is_zero() {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidEvery,
+                        span,
+                        "The interval of EVERY has to be longer than 0",
+                    )
+                    .with_help("e.g. `EVERY 5 SECONDS`"));
                 }
-                _ => Ok(None),
-            };
+                let between = if self.eat_keyword("BETWEEN") {
+                    let start = self.parse_time()?;
+                    if !self.eat_keyword("AND") {
+                        return Err(unexpected(self.peek(), "AND"));
+                    }
+                    Some((start, self.parse_time()?))
+                } else {
+                    None
+                };
+                Ok(Schedule::Every {
+                    interval,
+                    between,
+                    days: self.parse_days()?,
+                })
+            }
+            TokenKind::Ident(ident) if ident == "AT" => Ok(Schedule::At {
+                time: self.parse_time()?,
+                days: self.parse_days()?,
+            }),
+            _ => {
+                let token = self.advance();
+                match &token.kind {
+                    TokenKind::Str(expression) => Cron::parse(expression)
+                        .map(Schedule::Cron)
+                        .map_err(|message| {
+                            Diagnostic::error(ErrorCode::InvalidSchedule, token.span, message)
+                                .with_help(CRON_HELP)
+                        }),
+                    _ => Err(unexpected(&token, "a quoted cron expression").with_help(CRON_HELP)),
+                }
+            }
         }
-        _ => Ok(None),
     }
-}
 
-fn parse_iterator(
-    lines: &Vec<Vec<&str>>,
-    line_pos: &mut usize,
-    variable_map: *mut VariableMapType,
-) -> anyhow::Result<Iterative> {
-    let mut iterative = Iterative::new("".to_owned(), variable_map);
-
-    let mut first_iterative_passed = false;
-
-    while *line_pos < lines.len() {
-        let line = &lines[*line_pos];
-
-        let mut i = 0;
-
-        while i < line.len() {
-            let word = line[i];
-            match word {
-                "ITERATE" => {
-                    match first_iterative_passed {
-                        false => {
-                            first_iterative_passed = true;
-                            let key = match line.get(i + 1) {
-                                Some(key) => key,
-                                None => anyhow::bail!(
-                            "You haven't provided a Variable to ITERATE\nExample: ITERATE WINDOWS"
-                        ),
-                            };
+    /// `<amount> <unit>`, e.g. `5 SECONDS`. `keyword` is the statement it belongs to.
+    fn parse_duration(&mut self, keyword: &str) -> ParseResult<Duration> {
+        let token = self.advance();
+        let amount: u64 = match &token.kind {
+            TokenKind::Number(number) => number.parse().map_err(|_| {
+                Diagnostic::error(
+                    ErrorCode::InvalidEvery,
+                    token.span,
+                    format!("`{}` is not a valid number for {}", number, keyword),
+                )
+            })?,
+            _ => {
+                return Err(Diagnostic::error(
+                    ErrorCode::InvalidEvery,
+                    token.span,
+                    format!("Expected a number after {}, found {}", keyword, token.kind),
+                )
+                .with_help(format!("e.g. `{} 5 SECONDS`", keyword)))
+            }
+        };
 
-                            iterative.change_key(key.to_string());
-                        }
-                        true => iterative.push(parse_iterator(lines, line_pos, variable_map)?),
-                    };
-                }
-                "IF" => iterative.push(parse_conditional(lines, line_pos, variable_map)?),
-                "END" => return Ok(iterative),
+        let token = self.advance();
+        let time = match &token.kind {
+            TokenKind::Ident(unit) => match unit.as_str() {
+                "MILLISECONDS" => amount,
+                "SECONDS" => amount * 1000,
+                "MINUTES" => amount * 1000 * 60,
+                "HOURS" => amount * 1000 * 60 * 60,
                 _ => {
-                    if let Some(instruction) = parse_instruction(line, variable_map)? {
-                        iterative.push(instruction);
-                        break;
-                    }
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidEvery,
+                        token.span,
+                        format!("The time variant `{}` doesn't exist", unit),
+                    )
+                    .with_help(TIME_UNITS_HELP))
                 }
-            };
+            },
+            _ => {
+                return Err(Diagnostic::error(
+                    ErrorCode::InvalidEvery,
+                    token.span,
+                    format!("Expected a time variant, found {}", token.kind),
+                )
+                .with_help(TIME_UNITS_HELP))
+            }
+        };
 
-            i += 1;
-        }
+        Ok(Duration::from_millis(time))
+    }
 
-        *line_pos += 1;
+    /// Like `parse_duration`, but for durations that are calculated with while the rule runs.
+    fn parse_duration_value(&mut self, keyword: &str) -> ParseResult<ChronoDuration> {
+        let span = self.peek().span;
+        let duration = self.parse_duration(keyword)?;
+        ChronoDuration::from_std(duration).map_err(|_| {
+            Diagnostic::error(ErrorCode::InvalidNumber, span, "The duration is too long")
+        })
     }
 
-    Ok(iterative)
-}
+    /// A time of the day such as `09:00` or `17:30`.
+    fn parse_time(&mut self) -> ParseResult<NaiveTime> {
+        let hour = self.advance();
+        // The span ends with the last token of the time that's there, that's the hour itself,
+        // e.g. `Eof`, if the rule ends early.
+        let mut end = hour.span;
+        let time = match &hour.kind {
+            TokenKind::Number(hour) if self.peek().kind == TokenKind::Colon => {
+                end = self.advance().span;
+                let minute = self.advance();
+                match &minute.kind {
+                    TokenKind::Number(number) => {
+                        end = minute.span;
+                        hour.parse()
+                            .ok()
+                            .zip(number.parse().ok())
+                            .and_then(|(hour, minute)| NaiveTime::from_hms_opt(hour, minute, 0))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
 
-fn parse_conditional(
-    lines: &Vec<Vec<&str>>,
-    line_pos: &mut usize,
-    variable_map: *mut VariableMapType,
-) -> anyhow::Result<Conditional> {
-    #[derive(Debug, Eq, PartialEq)]
-    enum Condition {
-        If,
-        ElseIf,
-        Else,
+        time.ok_or_else(|| {
+            Diagnostic::error(
+                ErrorCode::InvalidSchedule,
+                Span {
+                    len: end.column + end.len - hour.span.column,
+                    ..hour.span
+                },
+                "Expected a time of the day",
+            )
+            .with_help("times are written as HH:MM, e.g. `09:00` or `17:30`")
+        })
     }
-    use Condition::*;
 
-    let mut conditional = Conditional::default();
+    /// The optional `ON WEEKDAYS`, `ON WEEKENDS` or `ON MONDAY, FRIDAY`, all days if it's
+    /// missing.
+    fn parse_days(&mut self) -> ParseResult<Days> {
+        if !self.eat_keyword("ON") {
+            return Ok(Days::ALL);
+        }
 
-    let mut first_if_passed = false;
+        let mut days = Days::NONE;
+        loop {
+            let token = self.advance();
+            let day = match &token.kind {
+                TokenKind::Ident(day) => match day.as_str() {
+                    "WEEKDAYS" => Some(Days::WEEKDAYS),
+                    "WEEKENDS" => Some(Days::WEEKENDS),
+                    day => day.parse::<Weekday>().ok().map(|day| Days::NONE.with(day)),
+                },
+                _ => None,
+            };
+            match day {
+                Some(day) => days = days.union(day),
+                None => {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidSchedule,
+                        token.span,
+                        format!("Expected a day, found {}", token.kind),
+                    )
+                    .with_help("e.g. `ON WEEKDAYS`, `ON WEEKENDS` or `ON MONDAY, FRIDAY`"))
+                }
+            }
+            if self.peek().kind != TokenKind::Comma {
+                return Ok(days);
+            }
+            self.advance();
+        }
+    }
 
-    let mut condition = If;
+    /// Parses statements until one of `terminators` is reached, the terminator itself is
+    /// consumed and returned. If the rule ends before that, the missing `END` is reported
+    /// and the `Eof` token is returned instead.
+    fn parse_block(&mut self, opener: &Token, terminators: &[&str]) -> (Vec<Stmt>, Token) {
+        let mut body = vec![];
 
-    let mut else_if_conditional_pos = 0;
+        loop {
+            self.skip_newlines();
 
-    while *line_pos < lines.len() {
-        let line = &lines[*line_pos];
+            if let TokenKind::Ident(ident) = &self.peek().kind {
+                if terminators.contains(&ident.as_str()) {
+                    return (body, self.advance());
+                }
+            }
 
-        let mut conditions_pos = 0;
+            if self.peek().kind == TokenKind::Eof {
+                self.diagnostics.push(Diagnostic::error(
+                    ErrorCode::MissingEnd,
+                    opener.span,
+                    format!("Missing END for {}", opener.kind),
+                ));
+                return (body, self.advance());
+            }
 
-        let mut else_if_conditions_pos = 0;
+            if self.is_keyword("DEFINE") || self.is_keyword("ON") {
+                // Still parsed as a block, otherwise its END would close this one.
+                let define = self.is_keyword("DEFINE");
+                let token = self.advance();
+                self.diagnostics.push(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    token.span,
+                    format!("{} can only be used outside of blocks", token.kind),
+                ));
+                if define {
+                    self.parse_define(&token);
+                } else {
+                    self.parse_on(&token);
+                }
+                continue;
+            }
 
-        let mut not_statement = false;
+            match self.parse_statement() {
+                Ok(stmt) => body.push(stmt),
+                Err(diagnostic) => self.recover(diagnostic),
+            }
+        }
+    }
 
-        let mut i = 0;
+    fn parse_statement(&mut self) -> ParseResult<Stmt> {
+        let token = self.advance();
 
-        while i < line.len() {
-            let word = line[i];
+        let name = match &token.kind {
+            TokenKind::Ident(name) => name.as_str(),
+            _ => return Err(unexpected(&token, "a statement")),
+        };
 
-            // Returns Option<_>, because Else statements do not have their own conditions.
-            let conditions = match condition {
-                If => match conditional.conditions.get_mut(conditions_pos) {
-                    Some(conditions) => Some(conditions),
-                    None => {
-                        conditional.conditions.push(Default::default());
-                        Some(&mut conditional.conditions[conditions_pos])
-                    }
-                },
-                ElseIf => {
-                    let else_if_conditionals = match conditional.else_if_conditionals.as_mut() {
-                        Some(else_if_conditionals) => else_if_conditionals,
-                        None => {
-                            conditional.else_if_conditionals = Some(Default::default());
-                            conditional.else_if_conditionals.as_mut().unwrap()
-                        }
-                    };
-                    match else_if_conditionals.get_mut(else_if_conditional_pos) {
-                        Some(conditional) => {
-                            conditional.conditions.push(Default::default());
-                            Some(&mut conditional.conditions[else_if_conditions_pos])
-                        }
-                        None => {
-                            else_if_conditionals.push(Default::default());
-
-                            let else_if_conditionals =
-                                conditional.else_if_conditionals.as_mut().unwrap();
-
-                            match else_if_conditionals[else_if_conditional_pos]
-                                .conditions
-                                .get_mut(else_if_conditions_pos)
-                            {
-                                Some(conditions) => Some(conditions),
-                                None => {
-                                    else_if_conditionals[else_if_conditional_pos]
-                                        .conditions
-                                        .push(Default::default());
-                                    Some(
-                                        &mut else_if_conditionals[else_if_conditional_pos]
-                                            .conditions[else_if_conditions_pos],
-                                    )
-                                }
-                            }
+        let stmt = match name {
+            "IF" => return Ok(self.parse_if(&token)),
+            "ITERATE" => return Ok(self.parse_iterate(&token)),
+            "SET" => {
+                let name = self.advance();
+                let name = match name.kind {
+                    TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => name,
+                    _ => return Err(unexpected(&name, "a variable name")),
+                };
+                self.expect(TokenKind::Assign)?;
+                let value = self.parse_expr()?;
+                self.declare(&name, self.type_of(&value));
+                Stmt::Set { name, value }
+            }
+            "CALL" => {
+                let name = self.advance();
+                let span = name.span;
+                let name = match name.kind {
+                    TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => name,
+                    _ => return Err(unexpected(&name, "a procedure name")),
+                };
+
+                self.expect(TokenKind::LParen)?;
+                let mut args = vec![];
+                if self.peek().kind == TokenKind::RParen {
+                    self.advance();
+                } else {
+                    loop {
+                        args.push(self.parse_expr()?);
+                        if self.expect_argument_separator()? {
+                            break;
                         }
                     }
                 }
-                Else => None,
-            };
 
-            match word {
-                "IF" => match first_if_passed {
-                    false => first_if_passed = true,
-                    true => {
-                        let executable: Box<dyn Executable> =
-                            parse_conditional(lines, line_pos, variable_map)?.into();
-                        match condition {
-                            If => conditional.executables.push(executable),
-                            ElseIf => conditional.else_if_conditionals.as_mut().unwrap()
-                                [else_if_conditional_pos]
-                                .executables
-                                .push(executable),
-                            Else => conditional
-                                .else_executables
-                                .as_mut()
-                                .unwrap()
-                                .push(executable),
-                        };
-                    }
-                },
-                "END" => {
-                    if condition == If {
-                        return Ok(conditional);
-                    }
-                    condition = If;
+                self.calls.push((name.clone(), span, args.len()));
+                Stmt::Call { name, args }
+            }
+            "PRINT" => Stmt::Print(self.parse_message()?),
+            "LOG" => {
+                let level = self.advance();
+                let level = match &level.kind {
+                    TokenKind::Ident(ident) => match ident.as_str() {
+                        "ERROR" => Some(log::Level::Error),
+                        "WARN" => Some(log::Level::Warn),
+                        "INFO" => Some(log::Level::Info),
+                        "DEBUG" => Some(log::Level::Debug),
+                        "TRACE" => Some(log::Level::Trace),
+                        _ => None,
+                    },
+                    _ => None,
                 }
-                "ELSEIF" => {
-                    else_if_conditional_pos = conditional
-                        .else_if_conditionals
-                        .as_ref()
-                        .map(|c| c.len())
-                        .unwrap_or(0);
-                    condition = ElseIf;
+                .ok_or_else(|| {
+                    Diagnostic::error(
+                        ErrorCode::InvalidLogLevel,
+                        level.span,
+                        format!("Expected a log level, found {}", level.kind),
+                    )
+                    .with_help("LOG accepts ERROR, WARN, INFO, DEBUG or TRACE")
+                })?;
+                Stmt::Log {
+                    level,
+                    message: self.parse_message()?,
+                }
+            }
+            "PERSIST" => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    token.span,
+                    "PERSIST can only be used at the top level of a rule",
+                ))
+            }
+            "BREAK" | "CONTINUE" if self.loops == 0 => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    token.span,
+                    format!("{} can only be used inside ITERATE", token.kind),
+                ))
+            }
+            "BREAK" => Stmt::Break,
+            "CONTINUE" => Stmt::Continue,
+            "TAG" => {
+                let category = self.parse_text("The category of TAG")?;
+                let value = self.parse_text("The value of TAG")?;
+                Stmt::Tag { category, value }
+            }
+            "SET_META" => {
+                let key = self.advance();
+                let key = match key.kind {
+                    TokenKind::Ident(key) | TokenKind::Str(key) => key,
+                    _ => return Err(unexpected(&key, "a key")),
+                };
+                Stmt::SetMeta {
+                    key,
+                    value: self.parse_expr()?,
                 }
-                "ELSE" => {
-                    condition = Else;
-                    conditional.else_executables = Some(Default::default());
+            }
+            "ASSIGN_PROJECT" => {
+                let span = self.peek().span;
+                let project = self.parse_text("The project of ASSIGN_PROJECT")?;
+                if let (Expr::Str(name), Some(projects)) = (&project, self.projects) {
+                    if find_project(projects, name).is_none() {
+                        let names: Vec<_> = projects.iter().map(|project| &*project.name).collect();
+                        return Err(Diagnostic::error(
+                            ErrorCode::UnknownProject,
+                            span,
+                            format!("There's no project with the name or the id `{}`", name),
+                        )
+                        .with_help(format!("Your projects are: {}.", names.join(", "))));
+                    }
                 }
-                "NOT" => not_statement = true,
-                "OR" => {
-                    match condition {
-                        If => conditions_pos += 1,
-                        ElseIf => else_if_conditions_pos += 1,
-                        _ => (),
-                    };
+                Stmt::AssignProject(project)
+            }
+            "NOTIFY" => {
+                let title = self.parse_text("The title of NOTIFY")?;
+                let body = self.parse_text("The body of NOTIFY")?;
+
+                let mut urgency = Urgency::Normal;
+                if self.eat_keyword("URGENCY") {
+                    let token = self.advance();
+                    urgency = match &token.kind {
+                        TokenKind::Ident(name) => Urgency::from_name(name),
+                        _ => None,
+                    }
+                    .ok_or_else(|| {
+                        unexpected(&token, "an urgency")
+                            .with_help("Your options are: LOW, NORMAL and CRITICAL.")
+                    })?;
                 }
-                "MATCH" => {
-                    let first = line[i - 1];
-
-                    let second = line[i + 1];
-
-                    let (is_string_first, first) = transform_str(first);
-
-                    let conditional_fn: ConditionalFn = match second {
-                        "IN" => {
-                            let regex_vec = parse_regex_array(line[i + 2])?;
-
-                            i += 1;
-
-                            Box::new(move || {
-                                if is_string_first {
-                                    for regex in &regex_vec {
-                                        debug!("{} {:?} {}", first, regex, regex.is_match(&first));
-                                        if regex.is_match(&first) {
-                                            return true;
-                                        }
-                                    }
-                                    return false;
-                                }
-
-                                let map = unsafe { &*variable_map };
-
-                                let execute = |string: &str| -> bool {
-                                    for regex in &regex_vec {
-                                        if regex.is_match(string) {
-                                            return true;
-                                        }
-                                    }
-                                    false
-                                };
-
-                                match map.get(first.as_str()) {
-                                    Some(Variable::RcStr(string)) => execute(string),
-                                    Some(Variable::ArcStr(string)) => execute(string),
-                                    _ => false,
-                                }
-                            })
-                        }
-                        _ => {
-                            let regex = Regex::new(&second[1..second.len() - 1])?;
-
-                            Box::new(move || {
-                                if is_string_first {
-                                    return regex.is_match(&first);
-                                }
 
-                                let map = unsafe { &*variable_map };
+                let timeout = if self.eat_keyword("TIMEOUT") {
+                    Some(self.parse_duration("TIMEOUT")?)
+                } else {
+                    None
+                };
 
-                                if let Some(Variable::RcStr(string)) = map.get(first.as_str()) {
-                                    return regex.is_match(string);
-                                }
+                Stmt::Notify {
+                    title,
+                    body,
+                    urgency,
+                    timeout,
+                }
+            }
+            "RUN" => {
+                // Only a quoted name, so that it's obvious which command a rule runs.
+                let name = self.advance();
+                let name = match name.kind {
+                    TokenKind::Str(name) => name,
+                    _ => {
+                        return Err(unexpected(&name, "the quoted name of a command")
+                            .with_help("the commands are set in the config of the user, e.g. `RUN \"timew-start\"`"))
+                    }
+                };
 
-                                false
-                            })
+                let mut args = vec![];
+                if self.eat_keyword("ARGS") {
+                    self.expect(TokenKind::LBracket)?;
+                    if self.peek().kind == TokenKind::RBracket {
+                        self.advance();
+                    } else {
+                        loop {
+                            args.push(self.parse_message()?);
+                            if self.expect_list_separator()? {
+                                break;
+                            }
                         }
-                    };
-
-                    if let Some(conditions) = conditions {
-                        conditions.push(conditional_fn)
                     }
                 }
-                "BIGGER" => {
-                    let (is_string_first, first) = transform_str(line[i - 1]);
-
-                    let (is_string_second, second) = transform_str(line[i + 1]);
-
-                    // -- ToDo
-                    // Instead of parsing the string as a number
-                    // everytime maybe parse it beforehand and just pass the number
-                    let conditional_fn = Box::new(move || {
-                        use Variable::*;
-
-                        let map = unsafe { &*variable_map };
-
-                        if is_string_first && is_string_second {
-                            let first_variable: usize = match first.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            let second_variable: usize = match second.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            return first_variable > second_variable;
-                        } else if !is_string_first && is_string_second {
-                            let first_variable = match map.get(first.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            let second_variable: usize = match second.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            return match *first_variable {
-                                Int(i) => i > second_variable,
-                                U64(i) => i > second_variable as u64,
-                                Float(i) => i > second_variable as f32,
-                                _ => false,
-                            };
-                        } else if is_string_first && !is_string_second {
-                            let first_variable: usize = match first.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            let second_variable = match map.get(second.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            return match *second_variable {
-                                Int(i) => i > first_variable,
-                                U64(i) => i > first_variable as u64,
-                                Float(i) => i > first_variable as f32,
-                                _ => false,
-                            };
-                        } else {
-                            let first_variable = match map.get(first.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            let second_variable = match map.get(second.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            return first_variable > second_variable;
-                        }
-                    });
 
-                    if let Some(conditions) = conditions {
-                        conditions.push(conditional_fn);
-                    }
+                Stmt::Run { name, args }
+            }
+            "HTTP_POST" => {
+                let url = self.parse_text("The URL of HTTP_POST")?;
+                if self.peek().kind != TokenKind::LBrace {
+                    return Err(unexpected(self.peek(), "a JSON object").with_help(
+                        "e.g. `HTTP_POST \"http://localhost:8080/status\" { \"title\": TITLE }`",
+                    ));
                 }
-                "LESSER" => {
-                    let (is_string_first, first) = transform_str(line[i - 1]);
-
-                    let (is_string_second, second) = transform_str(line[i + 1]);
-
-                    // -- ToDo
-                    // Instead of parsing the string as a number
-                    // everytime maybe parse it beforehand and just pass the number
-                    let conditional_fn = Box::new(move || {
-                        use Variable::*;
-
-                        let map = unsafe { &*variable_map };
-
-                        if is_string_first && is_string_second {
-                            let first_variable: usize = match first.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            let second_variable: usize = match second.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-                            return first_variable < second_variable;
-                        } else if !is_string_first && is_string_second {
-                            let first_variable = match map.get(first.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            let second_variable: usize = match second.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            return match *first_variable {
-                                Int(i) => i < second_variable,
-                                U64(i) => i < second_variable as u64,
-                                Float(i) => i < second_variable as f32,
-                                _ => false,
-                            };
-                        } else if is_string_first && !is_string_second {
-                            let first_variable: usize = match first.as_str().parse() {
-                                Ok(variable) => variable,
-                                Err(_) => return false,
-                            };
-
-                            let second_variable = match map.get(second.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            return match *second_variable {
-                                Int(i) => i < first_variable,
-                                U64(i) => i < first_variable as u64,
-                                Float(i) => i < first_variable as f32,
-                                _ => false,
-                            };
-                        } else {
-                            let first_variable = match map.get(first.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            let second_variable = match map.get(second.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            return first_variable < second_variable;
-                        }
-                    });
-
-                    if let Some(conditions) = conditions {
-                        conditions.push(conditional_fn);
+                let body = self.parse_json()?;
+                Stmt::HttpPost { url, body }
+            }
+            "SAVE_TO_DB" => Stmt::SaveToDb,
+            "GET_NETWORK_SSID" => Stmt::GetNetworkSsid,
+            "GET_PERIPHERALS" => Stmt::GetPeripherals,
+            "GET_WINDOWS" => Stmt::GetWindows,
+            "CAPTURE_SCREEN" => {
+                let screens = self.advance();
+                match &screens.kind {
+                    TokenKind::Str(screens) if screens == "ALL" => {
+                        Stmt::CaptureScreen(Screens::All)
+                    }
+                    TokenKind::Str(screens) if screens == "PRIMARY" => {
+                        Stmt::CaptureScreen(Screens::Primary)
+                    }
+                    _ => {
+                        return Err(Diagnostic::error(
+                            ErrorCode::InvalidScreen,
+                            screens.span,
+                            format!("Expected a screen to capture, found {}", screens.kind),
+                        )
+                        .with_help("CAPTURE_SCREEN accepts either \"PRIMARY\" or \"ALL\""))
                     }
                 }
-                "EQ" => {
-                    let space = if not_statement { 2 } else { 1 };
-
-                    let first = line[i - space];
+            }
+            "EVERY" | "AT" | "CRON" => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    token.span,
+                    format!("{} can only be used outside of blocks", token.kind),
+                ))
+            }
+            "ELSEIF" | "ELSE" | "END" => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnmatchedEnd,
+                    token.span,
+                    format!("Unexpected {} without a matching block", token.kind),
+                ))
+            }
+            _ => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnknownStatement,
+                    token.span,
+                    format!("Unknown statement {}", token.kind),
+                ))
+            }
+        };
 
-                    let is_string_first = is_string(first);
+        self.expect_end_of_statement()?;
 
-                    let first = if is_string_first {
-                        first[1..first.len() - 1].to_owned()
-                    } else {
-                        first.to_owned()
-                    };
+        Ok(stmt)
+    }
 
-                    let second = line[i + space];
+    /// Parses everything up to and including the line of the closing `END`.
+    fn parse_if(&mut self, opener: &Token) -> Stmt {
+        let mut branches = vec![];
+        let mut otherwise = None;
+
+        let mut header = self.parse_header((Expr::Str(String::new()), None), Self::parse_branch);
+
+        loop {
+            // The named groups of the MATCHes in the condition are variables in the branch.
+            let bindings = self.bindings.len();
+            let mut captures = vec![];
+            capture_names(&header.0, &mut captures);
+            self.bindings
+                .extend(captures.into_iter().map(|name| (name, Type::Str)));
+
+            let (body, terminator) = self.parse_block(opener, &["ELSEIF", "ELSE", "END"]);
+            self.bindings.truncate(bindings);
+
+            let (condition, sustained) = header;
+            branches.push(Branch {
+                condition,
+                sustained,
+                body,
+            });
+
+            match &terminator.kind {
+                TokenKind::Ident(ident) if ident == "ELSEIF" => {
+                    header =
+                        self.parse_header((Expr::Str(String::new()), None), Self::parse_branch);
+                }
+                TokenKind::Ident(ident) if ident == "ELSE" => {
+                    self.parse_header((), |_| Ok(()));
+                    let (body, terminator) = self.parse_block(opener, &["END"]);
+                    otherwise = Some(body);
+                    self.end_block(&terminator);
+                    break;
+                }
+                _ => {
+                    self.end_block(&terminator);
+                    break;
+                }
+            }
+        }
 
-                    let is_string_second = is_string(second);
+        Stmt::If {
+            branches,
+            otherwise,
+        }
+    }
 
-                    let second = if is_string_second {
-                        second[1..second.len() - 1].to_owned()
-                    } else {
-                        second.to_owned()
-                    };
+    /// `<condition> [FOR <duration>]`, the header of `IF` and `ELSEIF`.
+    fn parse_branch(&mut self) -> ParseResult<(Expr, Option<Sustained>)> {
+        let condition = self.parse_condition()?;
+        if !self.eat_keyword("FOR") {
+            return Ok((condition, None));
+        }
 
-                    let conditional_fn: ConditionalFn = Box::new(move || {
-                        let map = unsafe { &*variable_map };
+        let sustained = Sustained {
+            id: NEXT_SUSTAINED_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            duration: self.parse_duration_value("FOR")?,
+        };
+        Ok((condition, Some(sustained)))
+    }
 
-                        if is_string_first && !is_string_second {
-                            if let Some(variable) = map.get(second.as_str()) {
-                                return *variable == first;
-                            }
-                            false
-                        } else if !is_string_first && is_string_second {
-                            if let Some(variable) = map.get(first.as_str()) {
-                                return *variable == second;
-                            }
-                            false
-                        } else if is_string_first && is_string_second {
-                            first == second
-                        } else {
-                            let first = match map.get(first.as_str()) {
-                                Some(first) => first,
-                                None => return false,
-                            };
-
-                            let second = match map.get(second.as_str()) {
-                                Some(second) => second,
-                                None => return false,
-                            };
-
-                            *first == *second
-                        }
-                    });
+    /// `ITERATE [<name> IN] <list> [WHERE <condition>]`
+    fn parse_iterate(&mut self, opener: &Token) -> Stmt {
+        let bindings = self.bindings.len();
 
-                    if let Some(conditions) = conditions {
-                        conditions.push(conditional_fn);
+        let fallback = Selection {
+            binding: None,
+            key: String::new(),
+            filter: None,
+        };
+        let selection = self.parse_header(fallback, |parser| match parser.parse_selection()? {
+            (selection, None) => Ok(selection),
+            (_, Some((_, span))) => Err(Diagnostic::error(
+                ErrorCode::UnexpectedToken,
+                span,
+                "ITERATE goes through whole elements, only SUM takes a field",
+            )
+            .with_help("e.g. `ITERATE WINDOWS` or `ITERATE W IN WINDOWS`")),
+        });
+
+        self.loops += 1;
+        let (body, terminator) = self.parse_block(opener, &["END"]);
+        self.loops -= 1;
+        self.bindings.truncate(bindings);
+
+        self.end_block(&terminator);
+
+        Stmt::Iterate { selection, body }
+    }
+
+    /// `[<name> IN] <list>[.<field>] [WHERE <condition>]`, used by `ITERATE` and the
+    /// aggregate functions. The name stays bound until the caller truncates `bindings`.
+    /// Only `SUM` takes a field, it's returned together with its span.
+    fn parse_selection(&mut self) -> ParseResult<(Selection, Option<(String, Span)>)> {
+        let mut token = self.advance();
+        let mut binding = None;
+        if self.eat_keyword("IN") {
+            binding = match token.kind {
+                TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => Some(name),
+                _ => return Err(unexpected(&token, "a name for the elements")),
+            };
+            token = self.advance();
+        }
+
+        let (key, element) = match token.kind {
+            TokenKind::Ident(key) if !KEYWORDS.contains(&key.as_str()) => {
+                match self.variable_type(&key) {
+                    Type::List(element) if matches!(*element, Type::Window | Type::Any) => {
+                        (key, *element)
+                    }
+                    Type::Any => (key, Type::Any),
+                    ty => {
+                        return Err(type_mismatch(
+                            token.span,
+                            format!("Expected a list of windows, {} is of type {}", key, ty),
+                        ))
                     }
                 }
-                "IN" => {
-                    let space = if not_statement { 2 } else { 1 };
-
-                    i -= space;
-
-                    let (is_string_first, first) = transform_str(line[i]);
-
-                    i += space + 1;
-
-                    let second = line[i];
-
-                    let conditional_fn: ConditionalFn = if second.starts_with('[') {
-                        let vec = parse_array(second)?;
-                        Box::new(move || {
-                            let mut boolean = false;
-
-                            for string in &vec {
-                                if is_string(string) && is_string_first {
-                                    if *string == first {
-                                        boolean = true;
-                                        break;
-                                    }
-                                    continue;
-                                }
-
-                                let map = unsafe { &*variable_map };
-
-                                if let Some(variable) = map.get(string.as_str()) {
-                                    if is_string_first {
-                                        if *variable == first {
-                                            boolean = true;
-                                            break;
-                                        }
-                                        continue;
-                                    }
-
-                                    if let Some(second_variable) = map.get(first.as_str()) {
-                                        if *variable == *second_variable {
-                                            boolean = true;
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
+            }
+            _ => {
+                return Err(unexpected(&token, "a list of windows")
+                    .with_help("e.g. `WINDOWS` or `W IN WINDOWS`"))
+            }
+        };
 
-                            if not_statement {
-                                return !boolean;
-                            }
+        let field = if self.peek().kind == TokenKind::Dot {
+            self.advance();
+            let token = self.advance();
+            match token.kind {
+                TokenKind::Ident(field) => Some((field, token.span)),
+                _ => return Err(unexpected(&token, "a field name")),
+            }
+        } else {
+            None
+        };
 
-                            boolean
-                        })
-                    } else {
-                        let second = second.to_owned();
-                        Box::new(move || {
-                            let map = unsafe { &*variable_map };
-
-                            let variable = match map.get(second.as_str()) {
-                                Some(variable) => variable,
-                                None => return false,
-                            };
-
-                            let vec = match variable {
-                                Variable::Vector(vec) => vec,
-                                _ => return false,
-                            };
-
-                            let mut boolean = false;
-
-                            for variable in vec.iter() {
-                                if is_string_first {
-                                    if *variable == first {
-                                        boolean = true;
-                                        break;
-                                    }
-                                    continue;
-                                }
-
-                                if let Some(second_variable) = map.get(first.as_str()) {
-                                    if *variable == *second_variable {
-                                        boolean = true;
-                                        break;
-                                    }
-                                }
-                            }
+        if let Some(binding) = &binding {
+            self.bindings.push((binding.clone(), element));
+        }
 
-                            if not_statement {
-                                return !boolean;
-                            }
+        let filter = if self.eat_keyword("WHERE") {
+            Some(self.parse_condition()?)
+        } else {
+            None
+        };
 
-                            boolean
-                        })
-                    };
+        let selection = Selection {
+            binding,
+            key,
+            filter,
+        };
+        Ok((selection, field))
+    }
+
+    /// `COUNT(...)`, `ANY(...)`, `ALL(...)`, `FIRST(...)` or `SUM(...)`, `name` has already
+    /// been consumed.
+    fn parse_aggregate(&mut self, name: &Token) -> ParseResult<Expr> {
+        self.expect(TokenKind::LParen)?;
+
+        let bindings = self.bindings.len();
+        let result = self.parse_selection();
+        self.bindings.truncate(bindings);
+        let (selection, field) = result?;
+
+        let element = match self.variable_type(&selection.key) {
+            Type::List(element) => *element,
+            _ => Type::Any,
+        };
+
+        let function = match (&name.kind, field) {
+            (TokenKind::Ident(name), Some((field, span))) if name == "SUM" => {
+                let ty = match element {
+                    Type::Window => window_field_type(&field).ok_or_else(|| {
+                        Diagnostic::error(
+                            ErrorCode::UnknownField,
+                            span,
+                            format!("Windows have no field `{}`", field),
+                        )
+                        .with_help(WINDOW_FIELDS_HELP)
+                    })?,
+                    _ => Type::Any,
+                };
+                if !ty.is_numeric() && ty != Type::Duration {
+                    return Err(type_mismatch(
+                        span,
+                        format!(
+                            "SUM only adds up numbers and durations, {} is {}",
+                            field, ty
+                        ),
+                    ));
+                }
+                Aggregate::Sum(field)
+            }
+            (TokenKind::Ident(name), None) if name == "SUM" => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    self.peek().span,
+                    "SUM needs the field to add up",
+                )
+                .with_help("e.g. `SUM(WINDOWS.MEMORY)`"))
+            }
+            (_, Some((_, span))) => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnexpectedToken,
+                    span,
+                    format!(
+                        "{} goes through whole elements, only SUM takes a field",
+                        name.kind
+                    ),
+                ))
+            }
+            (TokenKind::Ident(name), None) => match name.as_str() {
+                "COUNT" => Aggregate::Count,
+                "ANY" => Aggregate::Any,
+                "ALL" => Aggregate::All,
+                _ => Aggregate::First,
+            },
+            _ => unreachable!(),
+        };
+
+        self.expect(TokenKind::RParen)?;
+
+        Ok(Expr::Aggregate {
+            function,
+            selection: Box::new(selection),
+        })
+    }
+
+    /// `END` has to be on a line of its own as well.
+    fn end_block(&mut self, terminator: &Token) {
+        if terminator.kind == TokenKind::Eof {
+            return;
+        }
+        if let Err(diagnostic) = self.expect_end_of_statement() {
+            self.recover(diagnostic);
+        }
+    }
+
+    /// The argument of `PRINT` and `LOG`, placeholders in a quoted value are filled in.
+    fn parse_message(&mut self) -> ParseResult<Expr> {
+        let token = self.peek().clone();
+        match self.parse_expr()? {
+            Expr::Str(string) if string.contains(['{', '}']) => {
+                parse_format(&string).map(Expr::Format).map_err(|message| {
+                    Diagnostic::error(ErrorCode::InvalidFormat, token.span, message)
+                        .with_help("e.g. `\"{TITLE}\"`, `\"{MEMORY:>10}\"` or `\"{CPU:.2}\"`")
+                })
+            }
+            expr => Ok(expr),
+        }
+    }
+
+    /// An expression that has to be text, placeholders are filled in like in `PRINT`.
+    /// `what` names it in the error.
+    fn parse_text(&mut self, what: &str) -> ParseResult<Expr> {
+        let span = self.peek().span;
+        let expr = self.parse_message()?;
+        let ty = self.type_of(&expr);
+        if !ty.is_str() {
+            return Err(type_mismatch(
+                span,
+                format!("{} has to be text, found a value of type {}", what, ty),
+            )
+            .with_help("use a placeholder to turn it into text, e.g. `\"{KEYSTROKES}\"`"));
+        }
+        Ok(expr)
+    }
+
+    /// `and (OR and)*`, the loosest binding of all operators.
+    fn parse_expr(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_and()?;
+
+        while self.is_keyword("OR") {
+            let token = self.advance();
+            let rhs = self.parse_and()?;
+            self.expect_bool(&token, &expr)?;
+            self.expect_bool(&token, &rhs)?;
+            expr = Expr::Or(Box::new(expr), Box::new(rhs));
+        }
+
+        Ok(expr)
+    }
+
+    /// The condition of an `IF` or `ELSEIF`, which must not be a plain value.
+    fn parse_condition(&mut self) -> ParseResult<Expr> {
+        let token = self.peek().clone();
+        let expr = self.parse_expr()?;
+        let ty = self.type_of(&expr);
+        if !ty.is_bool() {
+            return Err(Diagnostic::error(
+                ErrorCode::ExpectedCondition,
+                token.span,
+                format!("Expected a condition, found a value of type {}", ty),
+            )
+            .with_help("compare the value with EQ, BIGGER, LESSER, IN or MATCH"));
+        }
+        Ok(expr)
+    }
+
+    /// `not (AND not)*`
+    fn parse_and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_not()?;
+
+        while self.is_keyword("AND") {
+            let token = self.advance();
+            let rhs = self.parse_not()?;
+            self.expect_bool(&token, &expr)?;
+            self.expect_bool(&token, &rhs)?;
+            expr = Expr::And(Box::new(expr), Box::new(rhs));
+        }
 
-                    if let Some(conditions) = conditions {
-                        conditions.push(conditional_fn);
+        Ok(expr)
+    }
+
+    /// `NOT not | comparison`
+    fn parse_not(&mut self) -> ParseResult<Expr> {
+        if self.is_keyword("NOT") {
+            let token = self.advance();
+            let expr = self.parse_not()?;
+            self.expect_bool(&token, &expr)?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        self.parse_comparison()
+    }
+
+    /// `additive [[NOT] (EQ | BIGGER | LESSER | IN | MATCH) ...]`
+    fn parse_comparison(&mut self) -> ParseResult<Expr> {
+        let lhs = self.parse_additive()?;
+
+        let op = match &self.peek().kind {
+            TokenKind::Ident(op) => op.as_str(),
+            _ => "",
+        };
+
+        if !["NOT", "EQ", "BIGGER", "LESSER", "IN", "MATCH"].contains(&op) {
+            return Ok(lhs);
+        }
+
+        let negated = self.eat_keyword("NOT");
+
+        let token = self.advance();
+
+        let op = match &token.kind {
+            TokenKind::Ident(op) => op.as_str(),
+            _ => "",
+        };
+
+        let expr = match op {
+            "EQ" | "BIGGER" | "LESSER" => {
+                let op = match op {
+                    "EQ" => CompareOp::Eq,
+                    "BIGGER" => CompareOp::Bigger,
+                    _ => CompareOp::Lesser,
+                };
+                let rhs = self.parse_additive()?;
+                let (lhs, rhs) = self.check_comparison(&token, op, lhs, rhs)?;
+                Expr::Compare {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }
+            }
+            "IN" => {
+                let rhs = if self.peek().kind == TokenKind::LBracket {
+                    Expr::Array(self.parse_array()?)
+                } else {
+                    self.parse_additive()?
+                };
+                let (lhs, rhs) = self.check_comparison(&token, CompareOp::In, lhs, rhs)?;
+                Expr::Compare {
+                    op: CompareOp::In,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }
+            }
+            "MATCH" => {
+                let ty = self.type_of(&lhs);
+                if !ty.is_str() {
+                    return Err(type_mismatch(
+                        token.span,
+                        format!("MATCH only works on text, found a value of type {}", ty),
+                    ));
+                }
+                let regexes = if self.eat_keyword("IN") {
+                    self.expect(TokenKind::LBracket)?;
+                    let mut regexes = vec![];
+                    loop {
+                        regexes.push(self.parse_regex()?);
+                        if self.expect_list_separator()? {
+                            break;
+                        }
                     }
+                    regexes
+                } else {
+                    vec![self.parse_regex()?]
+                };
+                Expr::Match {
+                    value: Box::new(lhs),
+                    regexes,
+                }
+            }
+            _ => return Err(unexpected(&token, "EQ, BIGGER, LESSER, IN or MATCH")),
+        };
+
+        Ok(if negated {
+            Expr::Not(Box::new(expr))
+        } else {
+            expr
+        })
+    }
+
+    /// Rejects comparisons that can never hold and converts literals to the type of the
+    /// other side, e.g. `"10"` to `10` if it's compared to `KEYSTROKES`.
+    fn check_comparison(
+        &self,
+        token: &Token,
+        op: CompareOp,
+        lhs: Expr,
+        rhs: Expr,
+    ) -> ParseResult<(Expr, Expr)> {
+        if op == CompareOp::In {
+            let ty = self.type_of(&lhs);
+            let rhs = match rhs {
+                Expr::Array(elements) => Expr::Array(
+                    elements
+                        .into_iter()
+                        .map(|element| coerce_literal(element, &ty))
+                        .collect(),
+                ),
+                rhs => rhs,
+            };
+            return match self.type_of(&rhs) {
+                Type::List(element) if self.check_equality(&lhs, &rhs, &ty, &element) => {
+                    Ok((lhs, rhs))
+                }
+                Type::Any => Ok((lhs, rhs)),
+                Type::List(element) => Err(type_mismatch(
+                    token.span,
+                    format!("A value of type {} is never IN a List<{}>", ty, element),
+                )),
+                other => Err(type_mismatch(
+                    token.span,
+                    format!("IN needs a list on the right hand side, found {}", other),
+                )),
+            };
+        }
+
+        // Quoted values are always treated as numbers by BIGGER and LESSER, but EQ only
+        // converts them if the other side is known to be a number.
+        let target = |ty: Type| match ty {
+            Type::Int | Type::U64 | Type::Float => ty,
+            _ if op == CompareOp::Eq => Type::Str,
+            _ => Type::Any,
+        };
+        let lhs = coerce_literal(lhs, &target(self.type_of(&rhs)));
+        let rhs = coerce_literal(rhs, &target(self.type_of(&lhs)));
+        let (lhs_type, rhs_type) = (self.type_of(&lhs), self.type_of(&rhs));
+
+        if op == CompareOp::Eq {
+            if !self.check_equality(&lhs, &rhs, &lhs_type, &rhs_type) {
+                return Err(type_mismatch(
+                    token.span,
+                    format!(
+                        "A value of type {} is never EQ a value of type {}",
+                        lhs_type, rhs_type
+                    ),
+                ));
+            }
+        } else if !lhs_type.orderable(&rhs_type) {
+            let help = if lhs_type.is_temporal() || rhs_type.is_temporal() {
+                "durations need a unit, e.g. `5 MINUTES`, and dates only compare with dates"
+            } else {
+                "use EQ or MATCH to compare text"
+            };
+            return Err(type_mismatch(
+                token.span,
+                format!(
+                    "{} only compares numbers, dates or durations, found {} and {}",
+                    token.kind, lhs_type, rhs_type
+                ),
+            )
+            .with_help(help));
+        }
+
+        Ok((lhs, rhs))
+    }
+
+    /// Whether `lhs EQ rhs` can hold. A quoted value that isn't a number is never equal
+    /// to a number.
+    fn check_equality(&self, lhs: &Expr, rhs: &Expr, lhs_type: &Type, rhs_type: &Type) -> bool {
+        let text_and_number = |expr: &Expr, other: &Type| {
+            matches!(expr, Expr::Str(_)) && matches!(other, Type::Int | Type::U64 | Type::Float)
+        };
+        let elements = match rhs {
+            Expr::Array(elements) => elements.iter().collect(),
+            rhs => vec![rhs],
+        };
+        lhs_type.comparable(rhs_type)
+            && !text_and_number(lhs, rhs_type)
+            && !elements
+                .iter()
+                .any(|element| text_and_number(element, lhs_type))
+    }
+
+    /// Operands of `NOT`, `AND` and `OR` have to be conditions.
+    fn expect_bool(&self, token: &Token, expr: &Expr) -> ParseResult<()> {
+        let ty = self.type_of(expr);
+        if ty.is_bool() {
+            return Ok(());
+        }
+        Err(Diagnostic::error(
+            ErrorCode::ExpectedCondition,
+            token.span,
+            format!(
+                "{} expects conditions, found a value of type {}",
+                token.kind, ty
+            ),
+        )
+        .with_help("compare the value with EQ, BIGGER, LESSER, IN or MATCH"))
+    }
+
+    /// `multiplicative ((+ | -) multiplicative)*`
+    fn parse_additive(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_multiplicative()?;
+
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Sub,
+                _ => return Ok(expr),
+            };
+            let token = self.advance();
+            let rhs = self.parse_multiplicative()?;
+            expr = self.check_binary(&token, op, expr, rhs)?;
+        }
+    }
+
+    /// `primary ((* | / | %) primary)*`
+    fn parse_multiplicative(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Star => BinaryOp::Mul,
+                TokenKind::Slash => BinaryOp::Div,
+                TokenKind::Percent => BinaryOp::Rem,
+                _ => return Ok(expr),
+            };
+            let token = self.advance();
+            let rhs = self.parse_primary()?;
+            expr = self.check_binary(&token, op, expr, rhs)?;
+        }
+    }
+
+    fn check_binary(&self, token: &Token, op: BinaryOp, lhs: Expr, rhs: Expr) -> ParseResult<Expr> {
+        let (lhs_type, rhs_type) = (self.type_of(&lhs), self.type_of(&rhs));
+        if lhs_type.binary(op, &rhs_type).is_none() {
+            return Err(type_mismatch(
+                token.span,
+                format!("Can't calculate {} {} {}", lhs_type, op, rhs_type),
+            ));
+        }
+        Ok(Expr::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        })
+    }
+
+    /// The type of `expr` as far as it can be told before running the rule.
+    fn type_of(&self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Str(_) | Expr::Format(_) => Type::Str,
+            Expr::Int(_) => Type::Int,
+            Expr::Float(_) => Type::Float,
+            Expr::Duration(_) => Type::Duration,
+            Expr::Variable(name) => self.variable_type(name),
+            Expr::Field { name, field } => match self.variable_type(name) {
+                Type::Window => window_field_type(field).unwrap_or(Type::Any),
+                _ => Type::Any,
+            },
+            Expr::Aggregate {
+                function,
+                selection,
+            } => match (function, self.variable_type(&selection.key)) {
+                (Aggregate::Count, _) => Type::Int,
+                (Aggregate::Any | Aggregate::All, _) => Type::Bool,
+                (Aggregate::First, Type::List(element)) => *element,
+                (Aggregate::Sum(field), Type::List(element)) if *element == Type::Window => {
+                    window_field_type(field).unwrap_or(Type::Any)
+                }
+                _ => Type::Any,
+            },
+            Expr::Function { function, .. } => function.result(),
+            Expr::Array(elements) => {
+                let mut types = elements.iter().map(|element| self.type_of(element));
+                let first = types.next().unwrap_or(Type::Any);
+                let element = if types.all(|ty| ty == first) {
+                    first
+                } else {
+                    Type::Any
+                };
+                Type::List(Box::new(element))
+            }
+            Expr::Not(_)
+            | Expr::And(..)
+            | Expr::Or(..)
+            | Expr::Compare { .. }
+            | Expr::Match { .. } => Type::Bool,
+            Expr::Binary { op, lhs, rhs } => self
+                .type_of(lhs)
+                .binary(*op, &self.type_of(rhs))
+                .unwrap_or(Type::Any),
+        }
+    }
+
+    fn variable_type(&self, name: &str) -> Type {
+        let binding = self
+            .bindings
+            .iter()
+            .rev()
+            .find(|(binding, _)| binding == name)
+            .map(|(_, ty)| ty);
+        binding
+            .or_else(|| self.locals.as_ref().and_then(|locals| locals.get(name)))
+            .or_else(|| self.types.get(name))
+            .cloned()
+            .or_else(|| builtin_type(name))
+            .unwrap_or(Type::Any)
+    }
+
+    /// Records the type of a variable set with `SET`. A variable that gets values of
+    /// different types is only known at runtime. Inside a procedure it's a local variable
+    /// unless a global of that name exists already.
+    fn declare(&mut self, name: &str, ty: Type) {
+        let global = self.types.get(name).cloned().or_else(|| builtin_type(name));
+        let (scope, previous) = match &mut self.locals {
+            Some(locals) if locals.contains_key(name) || global.is_none() => {
+                let previous = locals.get(name).cloned();
+                (locals, previous)
+            }
+            _ => (&mut self.types, global),
+        };
+        let ty = match previous {
+            Some(previous) if previous != ty => Type::Any,
+            _ => ty,
+        };
+        scope.insert(name.to_owned(), ty);
+    }
+
+    /// A quoted value, a possibly negative number or duration, a variable or a parenthesized
+    /// expression.
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        if let (TokenKind::Number(_), TokenKind::Ident(unit)) =
+            (&self.peek().kind, &self.peek_next().kind)
+        {
+            if TIME_UNITS.contains(&unit.as_str()) {
+                return self.parse_duration_value("a duration").map(Expr::Duration);
+            }
+        }
+
+        let token = self.advance();
+        match &token.kind {
+            TokenKind::LParen => {
+                let expr = self.parse_expr()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(expr)
+            }
+            TokenKind::Str(string) => Ok(Expr::Str(string.clone())),
+            // `-5`, `-1.5` or `-5 MINUTES`, only numbers can be negated.
+            TokenKind::Minus if matches!(self.peek().kind, TokenKind::Number(_)) => {
+                Ok(match self.parse_primary()? {
+                    Expr::Int(int) => Expr::Int(-int),
+                    Expr::Float(float) => Expr::Float(-float),
+                    Expr::Duration(duration) => Expr::Duration(-duration),
+                    expr => expr,
+                })
+            }
+            TokenKind::Number(number) => parse_number(number).ok_or_else(|| {
+                Diagnostic::error(
+                    ErrorCode::InvalidNumber,
+                    token.span,
+                    format!("`{}` is not a valid number", number),
+                )
+            }),
+            TokenKind::Ident(ident)
+                if AGGREGATES.contains(&ident.as_str())
+                    && self.peek().kind == TokenKind::LParen =>
+            {
+                self.parse_aggregate(&token)
+            }
+            TokenKind::Ident(ident) if self.peek().kind == TokenKind::LParen => {
+                match Function::from_name(ident) {
+                    Some(function) => self.parse_function(function, token.span),
+                    None => Err(Diagnostic::error(
+                        ErrorCode::UnknownFunction,
+                        token.span,
+                        format!("There's no function `{}`", ident),
+                    )
+                    .with_help(FUNCTIONS_HELP)),
+                }
+            }
+            TokenKind::Ident(ident) if !KEYWORDS.contains(&ident.as_str()) => {
+                if self.peek().kind != TokenKind::Dot {
+                    return Ok(Expr::Variable(ident.clone()));
                 }
-                "ITERATE" => {
-                    let iterator = parse_iterator(&lines, line_pos, variable_map)?.into();
-                    match condition {
-                        If => conditional.executables.push(iterator),
-                        ElseIf => conditional.else_if_conditionals.as_mut().unwrap()
-                            [else_if_conditional_pos]
-                            .executables
-                            .push(iterator),
-                        Else => conditional
-                            .else_executables
-                            .as_mut()
-                            .unwrap()
-                            .push(iterator),
+
+                self.advance();
+                let field = self.advance();
+                let name = match &field.kind {
+                    TokenKind::Ident(name) => name.clone(),
+                    _ => return Err(unexpected(&field, "a field name")),
+                };
+
+                match self.variable_type(ident) {
+                    Type::Window if window_field_type(&name).is_none() => Err(Diagnostic::error(
+                        ErrorCode::UnknownField,
+                        field.span,
+                        format!("Windows have no field `{}`", name),
+                    )
+                    .with_help(WINDOW_FIELDS_HELP)),
+                    Type::Window | Type::Any => Ok(Expr::Field {
+                        name: ident.clone(),
+                        field: name,
+                    }),
+                    ty => Err(type_mismatch(
+                        token.span,
+                        format!("`{}` is of type {}, only windows have fields", ident, ty),
+                    )),
+                }
+            }
+            _ => Err(unexpected(&token, "a value")),
+        }
+    }
+
+    /// The arguments of `function`, its name has already been consumed.
+    fn parse_function(&mut self, function: Function, span: Span) -> ParseResult<Expr> {
+        self.expect(TokenKind::LParen)?;
+
+        let mut args = vec![];
+        if self.peek().kind == TokenKind::RParen {
+            self.advance();
+        } else {
+            loop {
+                let span = self.peek().span;
+                args.push((self.parse_expr()?, span));
+                if self.expect_argument_separator()? {
+                    break;
+                }
+            }
+        }
+
+        let params = function.params();
+        if params.len() != args.len() {
+            return Err(Diagnostic::error(
+                ErrorCode::ArgumentCount,
+                span,
+                format!(
+                    "The function `{}` takes {} argument(s), but {} were given",
+                    function.name(),
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+
+        for (index, ((arg, span), param)) in args.iter().zip(params).enumerate() {
+            let ty = self.type_of(arg);
+            let fits = match param {
+                Type::Str => ty.is_str(),
+                _ => matches!(ty, Type::Int | Type::U64 | Type::Any),
+            };
+            if !fits {
+                return Err(type_mismatch(
+                    *span,
+                    format!(
+                        "Argument {} of `{}` has to be of type {}, found {}",
+                        index + 1,
+                        function.name(),
+                        param,
+                        ty
+                    ),
+                ));
+            }
+        }
+
+        Ok(Expr::Function {
+            function,
+            args: args.into_iter().map(|(arg, _)| arg).collect(),
+        })
+    }
+
+    /// `{ "key": <json>, ... }`, `[<json>, ...]` or an expression, quoted values can have
+    /// placeholders.
+    fn parse_json(&mut self) -> ParseResult<Json> {
+        match self.peek().kind {
+            TokenKind::LBrace => {
+                self.advance();
+                let mut object = vec![];
+                if self.peek().kind == TokenKind::RBrace {
+                    self.advance();
+                    return Ok(Json::Object(object));
+                }
+                loop {
+                    let key = self.advance();
+                    let key = match key.kind {
+                        TokenKind::Str(key) => key,
+                        _ => return Err(unexpected(&key, "a quoted key")),
                     };
+                    self.expect(TokenKind::Colon)?;
+                    object.push((key, self.parse_json()?));
+
+                    let token = self.advance();
+                    match token.kind {
+                        TokenKind::Comma => {}
+                        TokenKind::RBrace => break,
+                        _ => return Err(unexpected(&token, "`,` or `}`")),
+                    }
                 }
-                _ => {
-                    if let Some(instruction) = parse_instruction(&line, variable_map)? {
-                        match condition {
-                            If => conditional.executables.push(instruction.into()),
-                            ElseIf => conditional.else_if_conditionals.as_mut().unwrap()
-                                [else_if_conditional_pos]
-                                .executables
-                                .push(instruction.into()),
-                            Else => conditional
-                                .else_executables
-                                .as_mut()
-                                .unwrap()
-                                .push(instruction.into()),
-                        };
+                Ok(Json::Object(object))
+            }
+            TokenKind::LBracket => {
+                self.advance();
+                let mut array = vec![];
+                if self.peek().kind == TokenKind::RBracket {
+                    self.advance();
+                    return Ok(Json::Array(array));
+                }
+                loop {
+                    array.push(self.parse_json()?);
+                    if self.expect_list_separator()? {
                         break;
                     }
                 }
-            };
+                Ok(Json::Array(array))
+            }
+            _ => self.parse_message().map(Json::Value),
+        }
+    }
+
+    fn parse_array(&mut self) -> ParseResult<Vec<Expr>> {
+        self.expect(TokenKind::LBracket)?;
+
+        let mut elements = vec![];
+
+        if self.peek().kind == TokenKind::RBracket {
+            self.advance();
+            return Ok(elements);
+        }
 
-            i += 1;
+        loop {
+            elements.push(self.parse_additive()?);
+            if self.expect_list_separator()? {
+                break;
+            }
         }
 
-        *line_pos += 1;
+        Ok(elements)
     }
 
-    Ok(conditional)
-}
+    /// Consumes either `,` or `]` and returns true if the list is closed.
+    fn expect_list_separator(&mut self) -> ParseResult<bool> {
+        let token = self.advance();
+        match &token.kind {
+            TokenKind::Comma => Ok(false),
+            TokenKind::RBracket => Ok(true),
+            _ => Err(unexpected(&token, "`,` or `]`")),
+        }
+    }
 
-/// Used to determine if passed value is a string and not a variable.
-fn is_string(string: impl AsRef<str>) -> bool {
-    let string = string.as_ref();
-    string.starts_with('\"') || string.starts_with('\'')
-}
+    /// Consumes either `,` or `)` and returns true if the list is closed.
+    fn expect_argument_separator(&mut self) -> ParseResult<bool> {
+        let token = self.advance();
+        match &token.kind {
+            TokenKind::Comma => Ok(false),
+            TokenKind::RParen => Ok(true),
+            _ => Err(unexpected(&token, "`,` or `)`")),
+        }
+    }
 
-/// Used to transform a &str into an owned string and remove **"** or **'** signs from the string
-/// if needed and it returns if the value was a string or a reference to the map.
-fn transform_str(string: impl AsRef<str>) -> (bool, String) {
-    let string = string.as_ref();
-    let is_string = is_string(string);
-    let string = if is_string {
-        string[1..string.len() - 1].to_owned()
-    } else {
-        string.to_owned()
-    };
-    (is_string, string)
+    fn parse_regex(&mut self) -> ParseResult<Regex> {
+        let token = self.advance();
+        match &token.kind {
+            TokenKind::Str(pattern) => {
+                let regex = Regex::new(pattern).map_err(|err| {
+                    // The regex crate renders its own snippet, only its last line is of
+                    // interest.
+                    let err = err.to_string();
+                    let reason = err.lines().last().unwrap_or_default();
+                    Diagnostic::error(
+                        ErrorCode::InvalidRegex,
+                        token.span,
+                        format!("Invalid regex: {}", reason.trim_start_matches("error: ")),
+                    )
+                })?;
+
+                // Named groups become variables.
+                if let Some(name) = regex
+                    .capture_names()
+                    .flatten()
+                    .find(|name| KEYWORDS.contains(name))
+                {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidRegex,
+                        token.span,
+                        format!("The group `{}` can't be named after a keyword", name),
+                    ));
+                }
+
+                Ok(regex)
+            }
+            _ => Err(unexpected(&token, "a quoted regex")),
+        }
+    }
 }
 
-fn is_array(string: impl AsRef<str>) -> bool {
-    let string = string.as_ref();
-    string.starts_with("[") && string.ends_with("]")
+/// `15` becomes an `Int`, `1.5` a `Float`.
+fn parse_number(number: &str) -> Option<Expr> {
+    if number.contains('.') {
+        number.parse().ok().map(Expr::Float)
+    } else {
+        number.parse().ok().map(Expr::Int)
+    }
 }
 
-fn parse_array(string: impl AsRef<str>) -> anyhow::Result<Vec<String>> {
-    let string = string.as_ref();
-    if !is_array(string) {
-        anyhow::bail!("You have an error in your array syntax: {}", string);
+/// The named groups of the `MATCH`es in `condition` that can hold together with it, i.e.
+/// not the ones behind `NOT`.
+fn capture_names(condition: &Expr, names: &mut Vec<String>) {
+    match condition {
+        Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+            capture_names(lhs, names);
+            capture_names(rhs, names);
+        }
+        Expr::Match { regexes, .. } => names.extend(
+            regexes
+                .iter()
+                .flat_map(|regex| regex.capture_names().flatten().map(str::to_owned)),
+        ),
+        _ => {}
     }
-    Ok(string[1..string.len() - 1]
-        .replace(' ', "")
-        .split(',')
-        .map(|s| s[1..s.len() - 1].to_owned())
-        .collect())
 }
 
-fn parse_regex_array(string: impl AsRef<str>) -> Result<Vec<Regex>, regex::Error> {
-    let string = string.as_ref();
-    let mut vec = vec![];
-    for string in string[1..string.len() - 1].replace(' ', "").split(',') {
-        vec.push(Regex::new(&string[1..string.len() - 1])?);
+/// Converts a literal to the numeric type it's compared with, e.g. `"10"` to `10` or `10`
+/// to `10.0`, so that it doesn't need to happen on every execution.
+fn coerce_literal(expr: Expr, like: &Type) -> Expr {
+    match (expr, like) {
+        (Expr::Str(string), Type::Int | Type::U64 | Type::Float | Type::Any) => {
+            match parse_number(&string) {
+                Some(Expr::Int(int)) if *like == Type::Float => Expr::Float(int as f32),
+                Some(number) => number,
+                None => Expr::Str(string),
+            }
+        }
+        (Expr::Int(int), Type::Float) => Expr::Float(int as f32),
+        (expr, _) => expr,
     }
+}
 
-    Ok(vec)
+fn type_mismatch(span: Span, message: String) -> Diagnostic {
+    Diagnostic::error(ErrorCode::TypeMismatch, span, message)
 }
 
-fn get_word_positions(line: &str) -> Vec<(usize, usize)> {
-    let mut word_positions = vec![];
-    let mut string_literal: Option<char> = None;
-    let mut previous_position = 0;
-    for (i, c) in line.chars().enumerate() {
+/// Splits `"Window {TITLE} uses {MEMORY:>10} bytes"` into literals and placeholders, `{{` and
+/// `}}` stand for literal braces.
+fn parse_format(string: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = string.chars().peekable();
+
+    while let Some(c) = chars.next() {
         match c {
-            ' ' if string_literal.is_none() => {
-                if !&line[previous_position..i].is_empty() {
-                    word_positions.push((previous_position, i));
-                }
-                previous_position = i + 1;
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                literal.push(c);
             }
-            '[' => {
-                string_literal = Some(c);
-                previous_position = i;
-            }
-            ']' => {
-                if let Some('[') = string_literal {
-                    word_positions.push((previous_position, i + 1));
-                    string_literal = None;
-                }
-                previous_position = i;
-            }
-            '\"' | '\'' | '`' => {
-                match string_literal {
-                    Some(a) if a == c => {
-                        word_positions.push((previous_position, i + 1));
-                        previous_position = i + 1;
-                        string_literal = None;
+            '}' => return Err("Unmatched `}`, write `}}` for a literal one".to_owned()),
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err("Unclosed `{`, write `{{` for a literal one".to_owned()),
                     }
-                    None => string_literal = Some(c),
-                    _ => (),
+                }
+
+                let (name, spec) = match placeholder.split_once(':') {
+                    Some((name, spec)) => (name.trim(), parse_format_spec(spec)?),
+                    None => (placeholder.trim(), FormatSpec::default()),
                 };
+
+                // A name or a field such as `W.TITLE`.
+                let valid = name.split('.').count() <= 2
+                    && name.split('.').all(|part| {
+                        !part.is_empty()
+                            && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+                            && !KEYWORDS.contains(&part)
+                    });
+                if !valid {
+                    return Err(format!("`{{{}}}` doesn't name a variable", placeholder));
+                }
+
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Variable {
+                    name: name.to_owned(),
+                    spec,
+                });
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+/// `[[fill]align][0][width][.precision][pattern]`
+fn parse_format_spec(spec: &str) -> Result<FormatSpec, String> {
+    let chars: Vec<char> = spec.chars().collect();
+    let mut format = FormatSpec::default();
+    let mut pos = 0;
+
+    let align = |c: char| match c {
+        '<' => Some(Align::Left),
+        '^' => Some(Align::Center),
+        '>' => Some(Align::Right),
+        _ => None,
+    };
+
+    if let Some(alignment) = chars.get(1).copied().and_then(align) {
+        format.fill = Some(chars[0]);
+        format.align = Some(alignment);
+        pos = 2;
+    } else if let Some(alignment) = chars.first().copied().and_then(align) {
+        format.align = Some(alignment);
+        pos = 1;
+    }
+
+    if chars.get(pos) == Some(&'0') {
+        format.zero = true;
+        pos += 1;
+    }
+
+    let number = |pos: &mut usize| {
+        let digits: String = chars[*pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        *pos += digits.len();
+        digits.parse().ok()
+    };
+
+    format.width = number(&mut pos);
+
+    if chars.get(pos) == Some(&'.') {
+        pos += 1;
+        format.precision = number(&mut pos);
+        if format.precision.is_none() {
+            return Err(format!("Missing the precision after `.` in `{}`", spec));
+        }
+    }
+
+    // Whatever is left is the pattern of a date, e.g. `%H:%M`.
+    if chars.get(pos) == Some(&'%') {
+        let pattern: String = chars[pos..].iter().collect();
+        if StrftimeItems::new(&pattern).any(|item| item == Item::Error) {
+            return Err(format!("Invalid date pattern `{}`", pattern));
+        }
+        format.pattern = Some(pattern);
+        pos = chars.len();
+    }
+
+    if pos != chars.len() {
+        return Err(format!("Invalid format specifier `{}`", spec));
+    }
+
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(source: &str) -> Program {
+        let (program, diagnostics) = parse(source);
+        assert_eq!(diagnostics, [], "{}", source);
+        program.unwrap()
+    }
+
+    #[test]
+    fn parses_every_statement() {
+        let program = parse_ok(
+            r#"EVERY 5 SECONDS
+GET_NETWORK_SSID
+GET_PERIPHERALS
+GET_WINDOWS
+CAPTURE_SCREEN "ALL"
+SET TOTAL = KEYSTROKES + MOUSE_CLICKS
+PERSIST COUNTER = 0 RESET WEEKLY
+IF TOTAL BIGGER 10 FOR 2 MINUTES
+  PRINT "{TOTAL}"
+ELSEIF NOT NETWORK_SSID EQ "office"
+  LOG WARN "away"
+ELSE
+  TAG "category" "idle"
+END
+ITERATE W IN WINDOWS WHERE W.MEMORY BIGGER 1000
+  IF W.TITLE MATCH IN ["a", "b"]
+    BREAK
+  END
+  CONTINUE
+END
+SET_META ticket TOTAL
+ASSIGN_PROJECT "Internal"
+NOTIFY "title" "body" URGENCY LOW TIMEOUT 3 SECONDS
+RUN "echo" ARGS [RULE_ID, "x"]
+HTTP_POST "http://localhost/" { "a": [1, TITLE], "b": { "c": "{TOTAL}" } }
+CALL SAVE()
+SAVE_TO_DB
+DEFINE SAVE()
+  SAVE_TO_DB
+END"#,
+        );
+
+        assert_eq!(program.schedules.len(), 1);
+        assert!(program.procedures.contains_key("SAVE"));
+        let body = &program.body;
+        assert_eq!(body.len(), 15);
+        assert!(matches!(body[0], Stmt::GetNetworkSsid));
+        assert!(matches!(body[1], Stmt::GetPeripherals));
+        assert!(matches!(body[2], Stmt::GetWindows));
+        assert!(matches!(body[3], Stmt::CaptureScreen(Screens::All)));
+        assert!(matches!(&body[4], Stmt::Set { name, .. } if name == "TOTAL"));
+        assert!(matches!(
+            &body[5],
+            Stmt::Persist { name, reset: Some(Reset::Weekly), .. } if name == "COUNTER"
+        ));
+        match &body[6] {
+            Stmt::If {
+                branches,
+                otherwise: Some(otherwise),
+            } => {
+                assert_eq!(branches.len(), 2);
+                let sustained = branches[0].sustained.unwrap();
+                assert_eq!(sustained.duration, ChronoDuration::minutes(2));
+                assert!(matches!(branches[0].body[..], [Stmt::Print(_)]));
+                assert!(matches!(branches[1].condition, Expr::Not(_)));
+                assert!(matches!(
+                    branches[1].body[..],
+                    [Stmt::Log {
+                        level: log::Level::Warn,
+                        ..
+                    }]
+                ));
+                assert!(matches!(otherwise[..], [Stmt::Tag { .. }]));
+            }
+            stmt => panic!("{:?}", stmt),
+        }
+        match &body[7] {
+            Stmt::Iterate { selection, body } => {
+                assert_eq!(selection.binding.as_deref(), Some("W"));
+                assert_eq!(selection.key, "WINDOWS");
+                assert!(selection.filter.is_some());
+                assert!(matches!(body[..], [Stmt::If { .. }, Stmt::Continue]));
             }
-            _ => (),
+            stmt => panic!("{:?}", stmt),
+        }
+        assert!(matches!(&body[8], Stmt::SetMeta { key, .. } if key == "ticket"));
+        assert!(matches!(body[9], Stmt::AssignProject(_)));
+        assert!(matches!(
+            body[10],
+            Stmt::Notify {
+                urgency: Urgency::Low,
+                timeout: Some(timeout),
+                ..
+            } if timeout == Duration::from_secs(3)
+        ));
+        assert!(matches!(&body[11], Stmt::Run { name, args } if name == "echo" && args.len() == 2));
+        assert!(matches!(
+            &body[12],
+            Stmt::HttpPost {
+                body: Json::Object(fields),
+                ..
+            } if fields.len() == 2
+        ));
+        assert!(
+            matches!(&body[13], Stmt::Call { name, args } if name == "SAVE" && args.is_empty())
+        );
+        assert!(matches!(body[14], Stmt::SaveToDb));
+    }
+
+    #[test]
+    fn parses_schedules_and_triggers() {
+        let program = parse_ok(
+            "EVERY 5 MINUTES BETWEEN 09:00 AND 18:00 ON WEEKDAYS\n\
+             AT 18:30 ON FRIDAY\n\
+             CRON \"*/5 9-17 * * 1-5\"\n\
+             ON WINDOW_CHANGE\n  SAVE_TO_DB\nEND\n\
+             ON IDLE_START\nEND",
+        );
+
+        assert_eq!(program.schedules.len(), 3);
+        assert!(matches!(program.schedules[0], Schedule::Every { .. }));
+        assert!(matches!(program.schedules[1], Schedule::At { .. }));
+        assert!(matches!(program.schedules[2], Schedule::Cron(_)));
+        let triggers: Vec<_> = program
+            .handlers
+            .iter()
+            .map(|handler| handler.trigger)
+            .collect();
+        assert_eq!(triggers, [Trigger::WindowChange, Trigger::IdleStart]);
+        assert!(program.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_intervals_of_zero() {
+        for source in ["EVERY 0 SECONDS", "EVERY 0 MILLISECONDS\nSAVE_TO_DB"] {
+            let (program, diagnostics) = parse(source);
+            assert!(program.is_none());
+            let found: Vec<_> = diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    (
+                        diagnostic.code,
+                        diagnostic.span,
+                        diagnostic.message.as_str(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                found,
+                [(
+                    ErrorCode::InvalidEvery,
+                    Span {
+                        line: 1,
+                        column: 7,
+                        len: 1
+                    },
+                    "The interval of EVERY has to be longer than 0"
+                )],
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn binds_operators() {
+        let program = parse_ok(
+            "EVERY 1 SECONDS\nIF KEYSTROKES + 2 * 3 BIGGER 1 OR NOT TITLE EQ \"a\" AND TITLE MATCH \"b\"\nEND",
+        );
+        let condition = match &program.body[0] {
+            Stmt::If { branches, .. } => &branches[0].condition,
+            stmt => panic!("{:?}", stmt),
+        };
+        let (lhs, rhs) = match condition {
+            Expr::Or(lhs, rhs) => (lhs, rhs),
+            expr => panic!("{:?}", expr),
+        };
+        match &**lhs {
+            Expr::Compare {
+                op: CompareOp::Bigger,
+                lhs,
+                ..
+            } => assert!(matches!(
+                &**lhs,
+                Expr::Binary { op: BinaryOp::Add, rhs, .. }
+                    if matches!(**rhs, Expr::Binary { op: BinaryOp::Mul, .. })
+            )),
+            expr => panic!("{:?}", expr),
+        }
+        assert!(matches!(&**rhs, Expr::And(lhs, _) if matches!(**lhs, Expr::Not(_))));
+    }
+
+    #[test]
+    fn parses_negative_numbers() {
+        let program = parse_ok("EVERY 1 SECONDS\nSET A = 5 - -3 + -1.5\nSET B = NOW + -5 MINUTES");
+        let value = |index: usize| match &program.body[index] {
+            Stmt::Set { value, .. } => value,
+            stmt => panic!("{:?}", stmt),
+        };
+        match value(0) {
+            Expr::Binary {
+                op: BinaryOp::Add,
+                lhs,
+                rhs,
+            } => {
+                assert!(matches!(**rhs, Expr::Float(float) if float == -1.5));
+                assert!(matches!(
+                    &**lhs,
+                    Expr::Binary { op: BinaryOp::Sub, rhs, .. } if matches!(**rhs, Expr::Int(-3))
+                ));
+            }
+            expr => panic!("{:?}", expr),
+        }
+        assert!(matches!(
+            value(1),
+            Expr::Binary { rhs, .. }
+                if matches!(**rhs, Expr::Duration(duration) if duration == ChronoDuration::minutes(-5))
+        ));
+
+        let (program, diagnostics) = parse("EVERY 1 SECONDS\nSET A = -TITLE");
+        assert!(program.is_none());
+        assert_eq!(diagnostics[0].code, ErrorCode::UnexpectedToken);
+    }
+
+    #[test]
+    fn reports_times_missing_at_the_end_of_the_rule() {
+        for (source, span) in [
+            ("AT   ", (1, 6, 0)),
+            ("AT # note", (1, 10, 0)),
+            ("AT 09:", (1, 4, 3)),
+            ("EVERY 5 MINUTES BETWEEN 09:00 AND ", (1, 35, 0)),
+            ("EVERY 5 MINUTES BETWEEN 09:00 AND\n", (1, 34, 1)),
+            ("AT 9:x0", (1, 4, 2)),
+        ] {
+            let (program, diagnostics) = parse(source);
+            assert!(program.is_none());
+            let Span { line, column, len } = diagnostics[0].span;
+            assert_eq!(
+                (diagnostics[0].code, (line, column, len)),
+                (ErrorCode::InvalidSchedule, span),
+                "{:?}",
+                source
+            );
+            diagnostics[0].render(source, "rule");
+        }
+    }
+
+    #[test]
+    fn checks_quoted_project_names() {
+        let projects = [Project {
+            id: "1".into(),
+            name: "Client X".into(),
+        }];
+        let parse = |source: &str| {
+            parse_with_projects(
+                format!("EVERY 1 MINUTES\n{}", source),
+                &mut Libraries::default(),
+                &projects,
+            )
         };
+
+        for source in [
+            "ASSIGN_PROJECT \"client x\"",
+            "ASSIGN_PROJECT \"1\"",
+            "ASSIGN_PROJECT LOWER(TITLE)",
+        ] {
+            let (program, diagnostics) = parse(source);
+            assert_eq!(diagnostics, [], "{}", source);
+            assert!(program.is_some());
+        }
+
+        let (program, diagnostics) = parse("ASSIGN_PROJECT \"Client Y\"");
+        assert!(program.is_none());
+        assert_eq!(diagnostics[0].code, ErrorCode::UnknownProject);
+        assert_eq!(
+            diagnostics[0].span,
+            Span {
+                line: 2,
+                column: 16,
+                len: 10
+            }
+        );
+        assert_eq!(
+            diagnostics[0].help.as_deref(),
+            Some("Your projects are: Client X.")
+        );
+    }
+
+    #[test]
+    fn reports_every_error_of_a_rule() {
+        let source = "EVERY 5 SECONDS
+IF TITLE EQ
+  PRITN TITLE
+  SET X = KEYSTROKES EQ \"abc\"
+END
+ITERATE WINDOWS
+  IF TITLE MATCH \"(\"
+  END
+END
+END
+";
+        let (program, diagnostics) = parse(source);
+        assert!(program.is_none());
+
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let Span { line, column, len } = diagnostic.span;
+                (diagnostic.code, line, column, len)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (ErrorCode::UnexpectedToken, 2, 12, 1),
+                (ErrorCode::UnknownStatement, 3, 3, 5),
+                (ErrorCode::TypeMismatch, 4, 22, 2),
+                (ErrorCode::InvalidRegex, 7, 18, 3),
+                (ErrorCode::UnmatchedEnd, 10, 1, 3),
+            ]
+        );
+
+        let rendered: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(source, "rule"))
+            .collect();
+        assert_eq!(
+            rendered[1],
+            "error[E0004]: Unknown statement `PRITN`
+ --> rule:3:3
+  |
+3 |   PRITN TITLE
+  |   ^^^^^"
+        );
+        assert_eq!(
+            rendered[4],
+            "error[E0006]: Unexpected `END` without a matching block
+  --> rule:10:1
+   |
+10 | END
+   | ^^^"
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_schedule_with_help() {
+        let (program, diagnostics) = parse("GET_WINDOWS\nPRINT TITLE");
+        assert!(program.is_none());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, ErrorCode::MissingEvery);
+        assert_eq!(
+            diagnostics[0].render("GET_WINDOWS\nPRINT TITLE", "rule"),
+            "error[E0007]: You haven't specified when the rule runs
+ --> rule:1:1
+  |
+1 | GET_WINDOWS
+  | ^
+  = help: start the rule with e.g. `EVERY 5 SECONDS` or `AT 17:30`, or add an `ON` block"
+        );
+    }
+
+    #[test]
+    fn knows_the_types_of_imported_constants() {
+        let mut sources = FxHashMap::default();
+        sources.insert("limits".to_owned(), "SET LIMIT = 5".to_owned());
+        sources.insert(
+            "common".to_owned(),
+            "IMPORT \"limits\"\nSET NAME = \"work\"".to_owned(),
+        );
+        let mut libraries = Libraries::new(sources);
+
+        let source = "EVERY 5 SECONDS
+IMPORT \"common\"
+IF LIMIT EQ \"10\" AND NAME EQ \"work\"
+  PRINT LIMIT
+END
+IF LIMIT EQ \"abc\"
+  PRINT LIMIT
+END
+";
+        let (program, diagnostics) = parse_with_libraries(source, &mut libraries);
+        assert!(program.is_none());
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.span.line))
+            .collect();
+        assert_eq!(found, [(ErrorCode::TypeMismatch, 6)]);
     }
-    if previous_position < line.len() - 1 {
-        word_positions.push((previous_position, line.len()));
+
+    /// Every rule in the documentation has to parse. The ones without a schedule are
+    /// fragments and get one.
+    #[test]
+    fn parses_the_documented_examples() {
+        let mut sources = FxHashMap::default();
+        sources.insert(
+            "common/idle-check".to_owned(),
+            "DEFINE SAVE_UNLESS_IDLE()\n  SAVE_TO_DB\nEND".to_owned(),
+        );
+        let mut libraries = Libraries::new(sources);
+
+        let documentation = include_str!("../../SCRIPTING.md");
+        let examples: Vec<_> = documentation
+            .split("\n```")
+            .skip(1)
+            .step_by(2)
+            .filter(|block| block.starts_with('\n') && !block.contains("error["))
+            .collect();
+        assert!(examples.len() > 10);
+
+        for example in examples {
+            let scheduled = example.lines().any(|line| {
+                ["EVERY ", "AT ", "CRON ", "ON "]
+                    .iter()
+                    .any(|keyword| line.starts_with(keyword))
+            });
+            let source = if scheduled {
+                example.to_owned()
+            } else {
+                format!("EVERY 1 MINUTES{}", example)
+            };
+            let (program, diagnostics) = parse_with_libraries(&source, &mut libraries);
+            assert_eq!(diagnostics, [], "{}", source);
+            assert!(program.is_some());
+        }
     }
-    word_positions
 }
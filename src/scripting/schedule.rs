@@ -0,0 +1,506 @@
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike, Weekday,
+};
+use std::{fmt, time::Duration};
+
+/// When a rule runs. A rule can have several schedules, it then runs whenever any of them
+/// is due. All times are in the local time zone of the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// `EVERY 5 MINUTES [BETWEEN 09:00 AND 18:00] [ON WEEKDAYS]`
+    Every {
+        interval: Duration,
+        /// The window of the day in which the rule runs, the end is exclusive. The window
+        /// wraps around midnight if the start is after the end.
+        between: Option<(NaiveTime, NaiveTime)>,
+        days: Days,
+    },
+    /// `AT 17:30 [ON FRIDAY]`
+    At { time: NaiveTime, days: Days },
+    /// `CRON "*/5 9-17 * * 1-5"`
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The next time the schedule is due, strictly after `after`, which is the time of the
+    /// previous run, in the time zone of `after`. `None` if it's never due again.
+    pub fn next_run<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        match self {
+            Schedule::Every {
+                interval,
+                between,
+                days,
+            } => {
+                let zone = after.timezone();
+                let candidate = after + ChronoDuration::from_std(*interval).ok()?;
+
+                let time = candidate.time();
+                let in_window = match between {
+                    None => true,
+                    Some((start, end)) if start <= end => time >= *start && time < *end,
+                    Some((start, end)) => time >= *start || time < *end,
+                };
+
+                if in_window && days.contains(candidate.weekday()) {
+                    return Some(candidate);
+                }
+
+                // Outside of the window, wait for the next one to open.
+                let start =
+                    between.map_or_else(|| NaiveTime::from_hms(0, 0, 0), |(start, _)| start);
+                next_on_days(zone, candidate.naive_local().date(), start, *days)
+                    .find(|at| *at >= candidate)
+            }
+            Schedule::At { time, days } => {
+                next_on_days(after.timezone(), after.naive_local().date(), *time, *days)
+                    .find(|at| *at > after)
+            }
+            Schedule::Cron(cron) => cron.next_run(after),
+        }
+    }
+}
+
+/// Something that happens on the computer of the user, `ON <trigger>` blocks run whenever
+/// it does, independent of the schedules of the rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    /// Another window got the focus.
+    WindowChange,
+    /// The user stopped using the mouse and the keyboard.
+    IdleStart,
+    /// The user is back after `IdleStart`.
+    IdleEnd,
+    /// The computer connected to another network or lost its connection.
+    NetworkChange,
+    /// The computer woke up from sleep.
+    Resume,
+}
+
+impl Trigger {
+    pub const ALL: [Trigger; 5] = [
+        Trigger::WindowChange,
+        Trigger::IdleStart,
+        Trigger::IdleEnd,
+        Trigger::NetworkChange,
+        Trigger::Resume,
+    ];
+
+    /// The name used after `ON`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Trigger::WindowChange => "WINDOW_CHANGE",
+            Trigger::IdleStart => "IDLE_START",
+            Trigger::IdleEnd => "IDLE_END",
+            Trigger::NetworkChange => "NETWORK_CHANGE",
+            Trigger::Resume => "RESUME",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|trigger| trigger.name() == name)
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// `time` in `zone` on every day of `days`, starting at `date` and going on for a week.
+fn next_on_days<Tz: TimeZone>(
+    zone: Tz,
+    date: NaiveDate,
+    time: NaiveTime,
+    days: Days,
+) -> impl Iterator<Item = DateTime<Tz>> {
+    (0..=7)
+        .map(move |offset| date + ChronoDuration::days(offset))
+        .filter(move |date| days.contains(date.weekday()))
+        .filter_map(move |date| local(&zone, date.and_time(time)))
+}
+
+/// The first instant the time in `zone` is `naive`, `None` if it's skipped, e.g. when the
+/// clocks are put forward. chrono moves skipped times past the gap instead, which would
+/// run the rule at a time it wasn't scheduled for.
+fn local<Tz: TimeZone>(zone: &Tz, naive: NaiveDateTime) -> Option<DateTime<Tz>> {
+    zone.from_local_datetime(&naive)
+        .earliest()
+        .filter(|at| at.naive_local() == naive)
+}
+
+/// A set of weekdays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Days {
+    pub const NONE: Days = Days(0);
+    pub const ALL: Days = Days(0b111_1111);
+    pub const WEEKDAYS: Days = Days(0b001_1111);
+    pub const WEEKENDS: Days = Days(0b110_0000);
+
+    pub fn with(self, day: Weekday) -> Self {
+        Days(self.0 | 1 << day.num_days_from_monday())
+    }
+
+    pub fn union(self, other: Days) -> Self {
+        Days(self.0 | other.0)
+    }
+
+    pub fn contains(self, day: Weekday) -> bool {
+        self.0 & 1 << day.num_days_from_monday() != 0
+    }
+}
+
+/// A classic five field cron expression: minute, hour, day of the month, month and day of
+/// the week. Every field accepts `*`, numbers, ranges such as `1-5`, steps such as `*/15`
+/// and comma separated lists of those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u32,
+    days_of_month: u32,
+    months: u16,
+    /// Sunday is 0, like in cron.
+    days_of_week: u8,
+    /// If both the day of the month and the day of the week are restricted, a day has to
+    /// match only one of them.
+    restricted_days: bool,
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        let (minutes, hours, days_of_month, months, days_of_week) = match fields[..] {
+            [minutes, hours, days_of_month, months, days_of_week] => {
+                (minutes, hours, days_of_month, months, days_of_week)
+            }
+            _ => {
+                return Err(format!(
+                    "A cron expression has 5 fields, found {}",
+                    fields.len()
+                ))
+            }
+        };
+
+        // 7 is Sunday as well.
+        let mut weekdays = parse_cron_field(days_of_week, 0, 7, "day of the week")?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            minutes: parse_cron_field(minutes, 0, 59, "minute")?,
+            hours: parse_cron_field(hours, 0, 23, "hour")? as u32,
+            days_of_month: parse_cron_field(days_of_month, 1, 31, "day of the month")? as u32,
+            months: parse_cron_field(months, 1, 12, "month")? as u16,
+            days_of_week: (weekdays & 0b111_1111) as u8,
+            restricted_days: days_of_month != "*" && days_of_week != "*",
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if self.months & 1 << date.month() == 0 {
+            return false;
+        }
+
+        let day_of_month = self.days_of_month & 1 << date.day() != 0;
+        let day_of_week = self.days_of_week & 1 << date.weekday().num_days_from_sunday() != 0;
+
+        if self.restricted_days {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    fn next_run<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let zone = after.timezone();
+        let after = after.naive_local();
+        let start =
+            after.date().and_hms(after.hour(), after.minute(), 0) + ChronoDuration::minutes(1);
+
+        // Some expressions, e.g. the 30th of February, are never due. Giving up after a few
+        // years covers everything else, including the 29th of February.
+        let mut date = start.date();
+        for _ in 0..366 * 5 {
+            if self.matches_day(date) {
+                for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                    for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                        let naive = date.and_hms(hour, minute, 0);
+                        if naive < start {
+                            continue;
+                        }
+                        if let Some(at) = local(&zone, naive) {
+                            return Some(at);
+                        }
+                    }
+                }
+            }
+            date = date.succ();
+        }
+
+        None
+    }
+}
+
+/// Parses one field of a cron expression into a bitmask of the allowed values.
+fn parse_cron_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, String> {
+    let invalid = || format!("`{}` is not a valid {} field", field, name);
+    let number = |string: &str| -> Result<u32, String> {
+        let number = string.parse().map_err(|_| invalid())?;
+        if number < min || number > max {
+            return Err(format!(
+                "{} is out of range for the {}, which goes from {} to {}",
+                number, name, min, max
+            ));
+        }
+        Ok(number)
+    };
+
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/10` means every 10th starting at 5.
+            None if part.contains('/') => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, LocalResult};
+
+    /// Central European Time, a zone with daylight saving time. The clocks go forward at
+    /// 01:00 UTC on the last Sunday of March and back on the last Sunday of October.
+    #[derive(Debug, Clone, Copy)]
+    struct Cet;
+
+    impl Cet {
+        fn winter() -> FixedOffset {
+            FixedOffset::east(60 * 60)
+        }
+
+        fn summer() -> FixedOffset {
+            FixedOffset::east(2 * 60 * 60)
+        }
+    }
+
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms(0, 0, 0))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let valid: Vec<_> = [Cet::summer(), Cet::winter()]
+                .iter()
+                .copied()
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - *offset)) == *offset)
+                .collect();
+            match valid[..] {
+                [] => LocalResult::None,
+                [offset] => LocalResult::Single(offset),
+                [earliest, latest] => LocalResult::Ambiguous(earliest, latest),
+                _ => unreachable!(),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms(0, 0, 0))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let last_sunday = |month| {
+                let last = NaiveDate::from_ymd(utc.year(), month, 31);
+                let back = last.weekday().num_days_from_sunday() as i64;
+                (last - ChronoDuration::days(back)).and_hms(1, 0, 0)
+            };
+            if *utc >= last_sunday(3) && *utc < last_sunday(10) {
+                Cet::summer()
+            } else {
+                Cet::winter()
+            }
+        }
+    }
+
+    /// A time in a zone with daylight saving time, whatever the zone of the machine is.
+    fn at(date: &str) -> DateTime<Cet> {
+        Cet.datetime_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn cron(expression: &str) -> Schedule {
+        Schedule::Cron(Cron::parse(expression).unwrap())
+    }
+
+    fn between(start: &str, end: &str) -> Schedule {
+        let time = |time| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        Schedule::Every {
+            interval: Duration::from_secs(60 * 60),
+            between: Some((time(start), time(end))),
+            days: Days::ALL,
+        }
+    }
+
+    #[test]
+    fn cron_steps_and_ranges() {
+        let schedule = cron("*/15 9-17/4 * * 1-5");
+        assert_eq!(
+            schedule.next_run(at("2024-03-04 09:50")),
+            Some(at("2024-03-04 13:00"))
+        );
+        assert_eq!(
+            schedule.next_run(at("2024-03-04 13:00")),
+            Some(at("2024-03-04 13:15"))
+        );
+        // Friday evening to Monday morning.
+        assert_eq!(
+            schedule.next_run(at("2024-03-08 17:45")),
+            Some(at("2024-03-11 09:00"))
+        );
+        assert_eq!(
+            cron("5/20 * * * *").next_run(at("2024-03-04 09:46")),
+            Some(at("2024-03-04 10:05"))
+        );
+        assert_eq!(
+            cron("0 12 * * 7").next_run(at("2024-03-04 09:00")),
+            Some(at("2024-03-10 12:00"))
+        );
+    }
+
+    #[test]
+    fn cron_restricted_days_match_either_field() {
+        // The 13th, which is a Wednesday, or any Friday.
+        let schedule = cron("0 0 13 * 5");
+        assert_eq!(
+            schedule.next_run(at("2024-03-04 12:00")),
+            Some(at("2024-03-08 00:00"))
+        );
+        assert_eq!(
+            schedule.next_run(at("2024-03-08 00:00")),
+            Some(at("2024-03-13 00:00"))
+        );
+    }
+
+    #[test]
+    fn cron_dates_that_never_or_rarely_exist() {
+        assert_eq!(cron("0 0 31 2 *").next_run(at("2024-03-04 12:00")), None);
+        assert_eq!(
+            cron("0 0 29 2 *").next_run(at("2024-03-01 00:00")),
+            Some(at("2028-02-29 00:00"))
+        );
+    }
+
+    #[test]
+    fn cron_rejects_invalid_fields() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn between_wraps_around_midnight() {
+        let schedule = between("22:00", "06:00");
+        assert_eq!(
+            schedule.next_run(at("2024-03-04 23:30")),
+            Some(at("2024-03-05 00:30"))
+        );
+        // 06:30 is outside, the window opens again in the evening.
+        assert_eq!(
+            schedule.next_run(at("2024-03-05 05:30")),
+            Some(at("2024-03-05 22:00"))
+        );
+
+        let schedule = between("09:00", "18:00");
+        assert_eq!(
+            schedule.next_run(at("2024-03-04 17:30")),
+            Some(at("2024-03-05 09:00"))
+        );
+    }
+
+    #[test]
+    fn skipped_local_times_are_left_out() {
+        // 02:30 doesn't exist on the 31st of March.
+        let time = NaiveTime::from_hms(2, 30, 0);
+        let schedule = Schedule::At {
+            time,
+            days: Days::ALL,
+        };
+        assert_eq!(
+            schedule.next_run(at("2024-03-30 03:00")),
+            Some(at("2024-04-01 02:30"))
+        );
+        assert_eq!(
+            cron("30 2 * * *").next_run(at("2024-03-30 03:00")),
+            Some(at("2024-04-01 02:30"))
+        );
+
+        // Intervals are counted in real time, an hour after 01:30 is 03:30.
+        let schedule = Schedule::Every {
+            interval: Duration::from_secs(60 * 60),
+            between: None,
+            days: Days::ALL,
+        };
+        assert_eq!(
+            schedule.next_run(at("2024-03-31 01:30")),
+            Some(at("2024-03-31 03:30"))
+        );
+    }
+
+    #[test]
+    fn repeated_local_times_run_once() {
+        // 02:30 happens twice on the 27th of October, the rule runs at the first one.
+        let next_run = cron("30 2 * * *").next_run(at("2024-10-27 00:00")).unwrap();
+        assert_eq!(
+            next_run.naive_local(),
+            NaiveDate::from_ymd(2024, 10, 27).and_hms(2, 30, 0)
+        );
+        assert_eq!(next_run.offset().local_minus_utc(), 2 * 60 * 60);
+        assert_eq!(
+            cron("30 2 * * *").next_run(next_run),
+            Some(at("2024-10-28 02:30"))
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use super::{Executable, ExecutionContext, Interpreter};
+use crate::capture::{
+    triggers::{Watcher, POLL_INTERVAL},
+    SharedCapturer,
+};
+use chrono::{DateTime, Local};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Runs all rules on one thread. The rules that are due at the same time run one after
+/// another in the same tick and share one capture of the windows, so a rule that takes
+/// long, e.g. because of `RUN` or `HTTP_POST`, delays the ones after it. If a rule has `ON`
+/// blocks, there's a tick every `POLL_INTERVAL` to check for triggers, which reuses the
+/// capture of the tick as well.
+pub struct Scheduler {
+    rules: Vec<(Interpreter, ExecutionContext)>,
+    capturer: Arc<Mutex<SharedCapturer>>,
+    /// When the scheduled rules run next by their index, the earliest first.
+    queue: BinaryHeap<Reverse<(DateTime<Local>, usize)>>,
+    /// Only created if a rule has `ON` blocks.
+    watcher: Option<Watcher>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            rules: vec![],
+            capturer: SharedCapturer::new(),
+            queue: BinaryHeap::new(),
+            watcher: None,
+        }
+    }
+
+    /// The capturer the contexts of the rules should be created with.
+    pub fn capturer(&self) -> Arc<Mutex<SharedCapturer>> {
+        self.capturer.clone()
+    }
+
+    /// Adds a rule and restores its `PERSIST`ed variables.
+    pub fn add(&mut self, interpreter: Interpreter, mut context: ExecutionContext) {
+        interpreter.restore(&mut context);
+        if interpreter.triggers().next().is_some() {
+            self.watcher
+                .get_or_insert_with(Watcher::default)
+                .watch(interpreter.triggers());
+        }
+        self.rules.push((interpreter, context));
+    }
+
+    /// Runs the rules until none of them is scheduled anymore and there are no triggers
+    /// to wait for.
+    pub fn run(mut self) {
+        // A rule that only reacts to triggers runs the rest of its body once, e.g. to set
+        // its variables.
+        self.tick();
+        let start = Local::now();
+        for index in 0..self.rules.len() {
+            match self.rules[index].0.next_run(start) {
+                Some(next_run) => self.queue.push(Reverse((next_run, index))),
+                None => self.execute(index),
+            }
+        }
+
+        loop {
+            let due = self.queue.peek().map(|Reverse((next_run, _))| {
+                (*next_run - Local::now()).to_std().unwrap_or_default()
+            });
+            let wait = match (&self.watcher, due) {
+                (Some(_), Some(due)) => due.min(POLL_INTERVAL),
+                (Some(_), None) => POLL_INTERVAL,
+                (None, Some(due)) => due,
+                (None, None) => {
+                    warn!("No rule is scheduled to run again");
+                    return;
+                }
+            };
+            let since = SystemTime::now();
+            thread::sleep(wait);
+
+            self.tick();
+            self.check_triggers(wait, since);
+            self.execute_due();
+        }
+    }
+
+    /// Runs the `ON` blocks of the triggers that fired since the last tick.
+    fn check_triggers(&mut self, wait: Duration, since: SystemTime) {
+        let watcher = match &mut self.watcher {
+            Some(watcher) => watcher,
+            None => return,
+        };
+        let event = if watcher.needs_capture() {
+            match self.capturer.lock().unwrap().capture() {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    debug!("{}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        for trigger in watcher.check(event.as_ref(), wait, since) {
+            debug!("{} fired", trigger);
+            for (interpreter, context) in &mut self.rules {
+                if let Err(err) = interpreter.trigger(trigger, context) {
+                    error!("{}", err);
+                }
+            }
+        }
+    }
+
+    /// Runs every rule whose time has come and schedules it again.
+    fn execute_due(&mut self) {
+        let now = Local::now();
+        while let Some(&Reverse((due, index))) = self.queue.peek() {
+            if due > now {
+                break;
+            }
+            self.queue.pop();
+            self.execute(index);
+
+            // Counted from when the rule was due rather than from now, so that rules with
+            // the same interval stay in the same tick. Unless the rule took so long that
+            // it would be due again right away.
+            let interpreter = &self.rules[index].0;
+            let finished = Local::now();
+            let next_run = match interpreter.next_run(due) {
+                Some(next_run) if next_run <= finished => interpreter.next_run(finished),
+                next_run => next_run,
+            };
+            match next_run {
+                Some(next_run) => self.queue.push(Reverse((next_run, index))),
+                None => warn!(
+                    "Rule {} isn't scheduled to run again",
+                    self.rules[index].1.rule.id
+                ),
+            }
+        }
+    }
+
+    fn execute(&mut self, index: usize) {
+        let (interpreter, context) = &mut self.rules[index];
+        if let Err(err) = interpreter.execute(context) {
+            error!("{}", err);
+        }
+    }
+
+    /// Starts a new tick, the rules that run from now on see a new capture.
+    fn tick(&self) {
+        self.capturer.lock().unwrap().tick();
+    }
+}
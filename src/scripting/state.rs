@@ -0,0 +1,458 @@
+use super::{Reset, Variable, VariableMapType};
+use chrono::{DateTime, Datelike, Duration, Local};
+use directories_next::ProjectDirs;
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// A value of a `PERSIST`ed variable as it's stored on disk. Unlike JSON it keeps the
+/// type, e.g. a duration stays a duration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+enum Stored {
+    Int(i64),
+    U64(u64),
+    Float(f32),
+    Bool(bool),
+    DateTime(DateTime<Local>),
+    /// In milliseconds.
+    Duration(i64),
+    Str(String),
+    List(Vec<Stored>),
+    Map(BTreeMap<String, Stored>),
+    Json(Value),
+}
+
+impl From<&Variable> for Stored {
+    fn from(variable: &Variable) -> Self {
+        match variable {
+            Variable::Int(int) => Stored::Int(*int),
+            Variable::U64(int) => Stored::U64(*int),
+            Variable::Float(float) => Stored::Float(*float),
+            Variable::Bool(boolean) => Stored::Bool(*boolean),
+            Variable::DateTime(date_time) => Stored::DateTime(*date_time),
+            Variable::Duration(duration) => Stored::Duration(duration.num_milliseconds()),
+            Variable::ArcStr(string) => Stored::Str((**string).clone()),
+            Variable::Vector(vec) => Stored::List(vec.iter().map(Stored::from).collect()),
+            Variable::Map(map) => Stored::Map(
+                map.iter()
+                    .map(|(key, variable)| (key.to_string(), Stored::from(variable)))
+                    .collect(),
+            ),
+            Variable::SerdeJsonVector(vec) => Stored::Json((**vec).clone().into()),
+            Variable::SerdeJson(value) => Stored::Json((**value).clone()),
+        }
+    }
+}
+
+impl From<Stored> for Variable {
+    fn from(stored: Stored) -> Self {
+        match stored {
+            Stored::Int(int) => Variable::Int(int),
+            Stored::U64(int) => Variable::U64(int),
+            Stored::Float(float) => Variable::Float(float),
+            Stored::Bool(boolean) => Variable::Bool(boolean),
+            Stored::DateTime(date_time) => Variable::DateTime(date_time),
+            Stored::Duration(milliseconds) => {
+                Variable::Duration(Duration::milliseconds(milliseconds))
+            }
+            Stored::Str(string) => Variable::ArcStr(Arc::new(string)),
+            Stored::List(list) => list
+                .into_iter()
+                .map(Variable::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Stored::Map(map) => map
+                .into_iter()
+                .map(|(key, stored)| (key.into(), Variable::from(stored)))
+                .collect::<VariableMapType>()
+                .into(),
+            Stored::Json(value) => Variable::SerdeJson(Box::new(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    value: Stored,
+    /// The period the value belongs to, e.g. `2022-08-15` for `RESET DAILY`. `None` if the
+    /// value never expires.
+    period: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    variables: BTreeMap<String, Entry>,
+}
+
+impl StateFile {
+    /// Parses the entries one by one, an entry that can't be parsed, e.g. a float that was
+    /// stored as `null`, is dropped without losing the others.
+    fn parse(stored: &str) -> serde_json::Result<Self> {
+        #[derive(Deserialize)]
+        struct Entries {
+            variables: BTreeMap<String, Value>,
+        }
+
+        let entries: Entries = serde_json::from_str(stored)?;
+        let variables = entries
+            .variables
+            .into_iter()
+            .filter_map(|(name, entry)| match serde_json::from_value(entry) {
+                Ok(entry) => Some((name, entry)),
+                Err(err) => {
+                    error!("Couldn't restore {}, it gets its default: {}", name, err);
+                    None
+                }
+            })
+            .collect();
+        Ok(Self { variables })
+    }
+}
+
+impl Reset {
+    /// The period `now` is in, a value stored in another period has expired. Weeks start on
+    /// Monday.
+    fn period(self, now: DateTime<Local>) -> String {
+        match self {
+            Reset::Daily => now.format("%Y-%m-%d").to_string(),
+            Reset::Weekly => {
+                let week = now.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+        }
+    }
+}
+
+/// The `PERSIST`ed variables of a rule. They're stored in a file per rule in the data
+/// directory, e.g. `~/.local/share/timetrackrs/state/<rule id>.json` on Linux, so they
+/// survive restarts.
+#[derive(Debug, Default)]
+pub(super) struct State {
+    /// Where the state of the rule is stored, set by the first `PERSIST` unless it's set
+    /// already.
+    path: Option<PathBuf>,
+    /// What was on disk when the first `PERSIST` ran, entries are taken out once restored.
+    /// The ones that are left are written back as they are.
+    stored: Option<StateFile>,
+    /// Every variable that has been `PERSIST`ed in this process, with the period of its
+    /// current value.
+    persisted: FxHashMap<String, Option<String>>,
+    /// What was last written, nothing is written if that hasn't changed.
+    written: Option<String>,
+}
+
+/// What `PERSIST` has to do with the variable.
+pub(super) enum Persisted {
+    /// It still has a value of the current period.
+    Current,
+    /// It gets the value it had before the restart.
+    Restored(Variable),
+    /// It gets its default, it's new or its value has expired.
+    Default,
+}
+
+impl State {
+    /// A state that's stored at `path` instead of the data directory.
+    #[cfg(test)]
+    pub(super) fn at(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            ..Self::default()
+        }
+    }
+
+    pub(super) fn persist(
+        &mut self,
+        rule_id: &str,
+        name: &str,
+        reset: Option<Reset>,
+        now: DateTime<Local>,
+    ) -> Persisted {
+        let period = reset.map(|reset| reset.period(now));
+
+        if let Some(current) = self.persisted.get(name) {
+            if *current == period {
+                return Persisted::Current;
+            }
+            self.persisted.insert(name.to_owned(), period);
+            return Persisted::Default;
+        }
+
+        let stored = self
+            .load(rule_id)
+            .variables
+            .remove(name)
+            .filter(|entry| entry.period == period);
+        self.persisted.insert(name.to_owned(), period);
+        match stored {
+            Some(entry) => Persisted::Restored(entry.value.into()),
+            None => Persisted::Default,
+        }
+    }
+
+    /// Undoes the last `persist` of `name`, e.g. because its default couldn't be evaluated.
+    /// The next `persist` gives it its default again.
+    pub(super) fn forget(&mut self, name: &str) {
+        self.persisted.remove(name);
+    }
+
+    fn load(&mut self, rule_id: &str) -> &mut StateFile {
+        if self.stored.is_none() {
+            if self.path.is_none() {
+                self.path = ProjectDirs::from("", "", "timetrackrs").map(|dirs| {
+                    dirs.data_dir()
+                        .join("state")
+                        .join(format!("{}.json", file_name(rule_id)))
+                });
+            }
+            let stored = match &self.path {
+                Some(path) => match fs::read_to_string(path) {
+                    Ok(stored) => StateFile::parse(&stored).unwrap_or_else(|err| {
+                        error!("Couldn't parse {}, starting over: {}", path.display(), err);
+                        StateFile::default()
+                    }),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => StateFile::default(),
+                    Err(err) => {
+                        error!("Couldn't read {}, starting over: {}", path.display(), err);
+                        StateFile::default()
+                    }
+                },
+                None => {
+                    error!("There's no data directory, PERSIST only lasts until the next restart");
+                    StateFile::default()
+                }
+            };
+            self.stored = Some(stored);
+        }
+        self.stored.as_mut().expect("just loaded")
+    }
+
+    /// Writes the current values of the `PERSIST`ed variables, if they changed, along with
+    /// the stored values that haven't been restored yet. The file is replaced at once, so a
+    /// crash leaves either the old or the new values behind.
+    pub(super) fn save(&mut self, variables: &VariableMapType) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) if !self.persisted.is_empty() => path,
+            _ => return Ok(()),
+        };
+
+        let mut state = StateFile {
+            variables: self
+                .stored
+                .as_ref()
+                .map(|stored| stored.variables.clone())
+                .unwrap_or_default(),
+        };
+        for (name, period) in &self.persisted {
+            if let Some(variable) = variables.get(name.as_str()) {
+                let entry = Entry {
+                    value: variable.into(),
+                    period: period.clone(),
+                };
+                state.variables.insert(name.clone(), entry);
+            }
+        }
+        let state = serde_json::to_string_pretty(&state)?;
+        if self.written.as_ref() == Some(&state) {
+            return Ok(());
+        }
+
+        let write = || -> io::Result<()> {
+            if let Some(directory) = path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            let temporary = path.with_extension("json.tmp");
+            let mut file = File::create(&temporary)?;
+            file.write_all(state.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary, path)
+        };
+        write().map_err(|err| anyhow!("Couldn't save the state to {}: {}", path.display(), err))?;
+
+        self.written = Some(state);
+        Ok(())
+    }
+}
+
+/// Rule ids are UUIDs, anything else is replaced to keep the id inside the directory.
+fn file_name(rule_id: &str) -> String {
+    rule_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A file in the temporary directory that's removed when the test is done.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "timetrackrs-state-{}-{}.json",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn day(year: i32, month: u32, day: u32) -> DateTime<Local> {
+        Local.ymd(year, month, day).and_hms(12, 0, 0)
+    }
+
+    fn variables(values: &[(&'static str, Variable)]) -> VariableMapType {
+        values
+            .iter()
+            .map(|(name, value)| ((*name).into(), value.clone()))
+            .collect()
+    }
+
+    fn restored(state: &mut State, name: &str, reset: Option<Reset>) -> Option<Variable> {
+        match state.persist("rule", name, reset, day(2022, 8, 15)) {
+            Persisted::Restored(value) => Some(value),
+            Persisted::Current => panic!("{} was persisted already", name),
+            Persisted::Default => None,
+        }
+    }
+
+    #[test]
+    fn daily_values_expire_every_day_and_weekly_ones_every_monday() {
+        assert_eq!(Reset::Daily.period(day(2022, 8, 15)), "2022-08-15");
+        assert_eq!(Reset::Daily.period(day(2022, 8, 16)), "2022-08-16");
+
+        // Sunday and Monday are in different weeks, Monday and Sunday in the same one.
+        assert_eq!(Reset::Weekly.period(day(2022, 8, 14)), "2022-W32");
+        assert_eq!(Reset::Weekly.period(day(2022, 8, 15)), "2022-W33");
+        assert_eq!(Reset::Weekly.period(day(2022, 8, 21)), "2022-W33");
+        // The days of a week at the turn of the year belong to the year of its Thursday.
+        assert_eq!(Reset::Weekly.period(day(2021, 1, 3)), "2020-W53");
+        assert_eq!(Reset::Weekly.period(day(2024, 12, 30)), "2025-W01");
+    }
+
+    #[test]
+    fn keeps_the_type_of_every_value() {
+        let mut map = VariableMapType::default();
+        map.insert("count".into(), Variable::Int(-3));
+        let values = vec![
+            Variable::Int(-5),
+            Variable::U64(u64::MAX),
+            Variable::Float(1.5),
+            Variable::Bool(true),
+            Variable::DateTime(Local.timestamp(1_660_557_600, 0)),
+            Variable::Duration(Duration::milliseconds(90_500)),
+            Variable::ArcStr(Arc::new("text".to_owned())),
+            vec![Variable::Int(1), "a".into()].into(),
+            map.into(),
+            Variable::SerdeJson(Box::new(serde_json::json!({ "a": [1, 2] }))),
+        ];
+
+        for value in values {
+            let stored = serde_json::to_string(&Stored::from(&value)).unwrap();
+            let stored: Stored = serde_json::from_str(&stored).unwrap();
+            assert_eq!(Variable::from(stored), value);
+        }
+    }
+
+    #[test]
+    fn restores_the_values_after_a_restart() {
+        let file = TempFile::new("restart");
+
+        let mut state = State::at(file.0.clone());
+        assert!(restored(&mut state, "TOTAL", None).is_none());
+        assert!(restored(&mut state, "TODAY", Some(Reset::Daily)).is_none());
+        assert!(matches!(
+            state.persist("rule", "TOTAL", None, day(2022, 8, 15)),
+            Persisted::Current
+        ));
+        state
+            .save(&variables(&[
+                ("TOTAL", Variable::Int(7)),
+                ("TODAY", Variable::Int(2)),
+                ("OTHER", Variable::Int(1)),
+            ]))
+            .unwrap();
+
+        let mut state = State::at(file.0.clone());
+        assert_eq!(restored(&mut state, "TOTAL", None), Some(Variable::Int(7)));
+        assert_eq!(
+            restored(&mut state, "TODAY", Some(Reset::Daily)),
+            Some(Variable::Int(2))
+        );
+        assert!(restored(&mut state, "OTHER", None).is_none());
+
+        // A day later the daily value has expired.
+        let mut state = State::at(file.0.clone());
+        assert!(matches!(
+            state.persist("rule", "TODAY", Some(Reset::Daily), day(2022, 8, 16)),
+            Persisted::Default
+        ));
+    }
+
+    #[test]
+    fn keeps_the_stored_values_that_were_not_restored_yet() {
+        let file = TempFile::new("merge");
+
+        let mut state = State::at(file.0.clone());
+        restored(&mut state, "A", None);
+        restored(&mut state, "B", None);
+        state
+            .save(&variables(&[
+                ("A", Variable::Int(1)),
+                ("B", Variable::Int(2)),
+            ]))
+            .unwrap();
+
+        // After a restart `A` is saved before `B` is persisted again.
+        let mut state = State::at(file.0.clone());
+        restored(&mut state, "A", None);
+        state.save(&variables(&[("A", Variable::Int(3))])).unwrap();
+
+        let mut state = State::at(file.0.clone());
+        assert_eq!(restored(&mut state, "A", None), Some(Variable::Int(3)));
+        assert_eq!(restored(&mut state, "B", None), Some(Variable::Int(2)));
+    }
+
+    #[test]
+    fn a_value_that_cant_be_read_back_only_loses_itself() {
+        let file = TempFile::new("nan");
+
+        let mut state = State::at(file.0.clone());
+        restored(&mut state, "RATIO", None);
+        restored(&mut state, "TOTAL", None);
+        state
+            .save(&variables(&[
+                ("RATIO", Variable::Float(f32::NAN)),
+                ("TOTAL", Variable::Int(7)),
+            ]))
+            .unwrap();
+
+        let mut state = State::at(file.0.clone());
+        assert!(restored(&mut state, "RATIO", None).is_none());
+        assert_eq!(restored(&mut state, "TOTAL", None), Some(Variable::Int(7)));
+    }
+}
@@ -0,0 +1,150 @@
+use super::BinaryOp;
+use std::fmt;
+
+/// The type of an expression as far as it's known while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    U64,
+    Float,
+    Bool,
+    Str,
+    /// A point in time, e.g. `NOW`.
+    DateTime,
+    /// A span of time, e.g. `UPTIME_OF_PROCESS` or `2 HOURS`.
+    Duration,
+    List(Box<Type>),
+    /// A window of `WINDOWS`, its fields are set by `ITERATE`.
+    Window,
+    /// Only known once the rule runs, e.g. the parameters of a procedure.
+    Any,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::U64 => write!(f, "U64"),
+            Type::Float => write!(f, "Float"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Str => write!(f, "Str"),
+            Type::DateTime => write!(f, "DateTime"),
+            Type::Duration => write!(f, "Duration"),
+            Type::List(element) => write!(f, "List<{}>", element),
+            Type::Window => write!(f, "Window"),
+            Type::Any => write!(f, "Any"),
+        }
+    }
+}
+
+impl Type {
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::U64 | Type::Float | Type::Any)
+    }
+
+    /// Whether a value of this type can be used as a condition.
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Type::Bool | Type::Any)
+    }
+
+    pub fn is_str(&self) -> bool {
+        matches!(self, Type::Str | Type::Any)
+    }
+
+    pub fn is_temporal(&self) -> bool {
+        matches!(self, Type::DateTime | Type::Duration)
+    }
+
+    /// Whether `BIGGER` and `LESSER` can compare values of the two types. Dates and
+    /// durations only compare with their own kind, a bare number has no unit.
+    pub fn orderable(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, other) | (other, Type::Any) => other.is_numeric() || other.is_temporal(),
+            (Type::DateTime, Type::DateTime) | (Type::Duration, Type::Duration) => true,
+            _ => self.is_numeric() && other.is_numeric(),
+        }
+    }
+
+    /// Whether `EQ` can ever hold for values of the two types. Quoted values are compared
+    /// by their text, so they can equal anything but lists, windows, dates and durations.
+    pub fn comparable(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::List(_), _) | (_, Type::List(_)) | (Type::Window, _) | (_, Type::Window) => {
+                false
+            }
+            (Type::DateTime, _)
+            | (_, Type::DateTime)
+            | (Type::Duration, _)
+            | (_, Type::Duration) => self == other,
+            (Type::Str, _) | (_, Type::Str) => true,
+            (Type::Bool, Type::Bool) => true,
+            (Type::Bool, _) | (_, Type::Bool) => false,
+            _ => true,
+        }
+    }
+
+    /// The type of `self op other`, `None` if the operator can't be applied.
+    pub fn binary(&self, op: BinaryOp, other: &Type) -> Option<Type> {
+        if op == BinaryOp::Add && (*self == Type::Str || *other == Type::Str) {
+            return Some(Type::Str);
+        }
+
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => Some(Type::Any),
+            (Type::DateTime, Type::DateTime) if op == BinaryOp::Sub => Some(Type::Duration),
+            (Type::DateTime, Type::Duration) if matches!(op, BinaryOp::Add | BinaryOp::Sub) => {
+                Some(Type::DateTime)
+            }
+            (Type::Duration, Type::DateTime) if op == BinaryOp::Add => Some(Type::DateTime),
+            (Type::Duration, Type::Duration) if matches!(op, BinaryOp::Add | BinaryOp::Sub) => {
+                Some(Type::Duration)
+            }
+            (Type::Duration, other)
+                if matches!(op, BinaryOp::Mul | BinaryOp::Div) && other.is_numeric() =>
+            {
+                Some(Type::Duration)
+            }
+            (other, Type::Duration) if op == BinaryOp::Mul && other.is_numeric() => {
+                Some(Type::Duration)
+            }
+            (Type::DateTime | Type::Duration, _) | (_, Type::DateTime | Type::Duration) => None,
+            (Type::Float, other) | (other, Type::Float) if other.is_numeric() => Some(Type::Float),
+            (Type::Int, Type::Int) => Some(Type::Int),
+            (Type::Int, Type::U64) | (Type::U64, Type::Int) | (Type::U64, Type::U64) => {
+                Some(Type::U64)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The types of the variables that are set by the daemon and the Get Statements.
+pub fn builtin_type(name: &str) -> Option<Type> {
+    let ty = match name {
+        "RULE_ID" | "RULE_BODY" | "OS_TYPE" | "VERSION" | "HOSTNAME" | "USERNAME"
+        | "MACHINE_ID" | "NETWORK_SSID" | "RUN_STDOUT" => Type::Str,
+        "BATTERIES" | "KEYSTROKES" | "MOUSE_CLICKS" | "RUN_EXIT_CODE" => Type::Int,
+        "SECONDS_SINCE_LAST_INPUT" => Type::U64,
+        "NOW" | "TODAY" => Type::DateTime,
+        "HOUR" | "MINUTE" => Type::Int,
+        "WEEKDAY" | "DATE" => Type::Str,
+        "WINDOWS" => Type::List(Box::new(Type::Window)),
+        "PROJECTS" => Type::List(Box::new(Type::Any)),
+        _ => return window_field_type(name),
+    };
+    Some(ty)
+}
+
+/// The variables `ITERATE WINDOWS` sets for every window.
+pub fn window_field_type(name: &str) -> Option<Type> {
+    let ty = match name {
+        "TITLE" | "PROCESS_NAME" | "CMD" | "EXE" | "CWD" | "STATUS" => Type::Str,
+        "MEMORY" => Type::Int,
+        "START_TIME" => Type::U64,
+        "CPU_USAGE" => Type::Float,
+        "UPTIME_OF_PROCESS" => Type::Duration,
+        _ => return None,
+    };
+    Some(ty)
+}
@@ -0,0 +1,129 @@
+//! Sends a notification to a stand-in for the notification server on the session bus.
+//! Skipped if there's no session bus, e.g. in CI, or if a real notification server is
+//! running on it.
+#![cfg(target_os = "linux")]
+
+use dbus::{
+    arg::{prop_cast, PropMap},
+    blocking::{stdintf::org_freedesktop_dbus::RequestNameReply, Connection},
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+};
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use timetrackrs::notification::{notify, Notification, Urgency};
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+
+/// The arguments of a `Notify` call that are set by timetrackrs.
+#[derive(Debug, PartialEq)]
+struct Received {
+    app_name: String,
+    summary: String,
+    body: String,
+    urgency: Option<u8>,
+    timeout: i32,
+}
+
+/// Owns the name of the notification server until the first `Notify` call, which is
+/// answered with the id 7 and sent to `received`. `None` if the name is taken.
+fn serve(received: mpsc::Sender<Received>) -> Option<thread::JoinHandle<()>> {
+    let (ready, started) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let connection = Connection::new_session().unwrap();
+        let reply = connection
+            .request_name(DESTINATION, false, false, true)
+            .unwrap();
+        ready.send(reply == RequestNameReply::PrimaryOwner).unwrap();
+        if reply != RequestNameReply::PrimaryOwner {
+            return;
+        }
+
+        let (done, finished) = mpsc::channel();
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                if message.member().as_deref() != Some("Notify") {
+                    return true;
+                }
+                let (app_name, _, _, summary, body, _, hints, timeout): (
+                    String,
+                    u32,
+                    String,
+                    String,
+                    String,
+                    Vec<String>,
+                    PropMap,
+                    i32,
+                ) = message.read_all().unwrap();
+                let urgency = prop_cast::<u8>(&hints, "urgency").copied();
+                connection
+                    .send(message.method_return().append1(7_u32))
+                    .unwrap();
+                received
+                    .send(Received {
+                        app_name,
+                        summary,
+                        body,
+                        urgency,
+                        timeout,
+                    })
+                    .unwrap();
+                done.send(()).unwrap();
+                false
+            }),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while finished.try_recv().is_err() && Instant::now() < deadline {
+            connection.process(Duration::from_millis(100)).unwrap();
+        }
+    });
+
+    if started.recv().unwrap() {
+        Some(server)
+    } else {
+        server.join().unwrap();
+        None
+    }
+}
+
+#[test]
+fn sends_notifications_to_the_session_bus() {
+    if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() {
+        eprintln!("Skipped, DBUS_SESSION_BUS_ADDRESS isn't set");
+        return;
+    }
+
+    let (received, notifications) = mpsc::channel();
+    let server = match serve(received) {
+        Some(server) => server,
+        None => {
+            eprintln!("Skipped, a notification server is running");
+            return;
+        }
+    };
+
+    notify(&Notification {
+        title: "Take a break".to_owned(),
+        body: "You've been typing for 2h".to_owned(),
+        urgency: Urgency::Critical,
+        timeout: Some(Duration::from_secs(10)),
+    })
+    .unwrap();
+
+    assert_eq!(
+        notifications.recv_timeout(Duration::from_secs(5)).unwrap(),
+        Received {
+            app_name: "timetrackrs".to_owned(),
+            summary: "Take a break".to_owned(),
+            body: "You've been typing for 2h".to_owned(),
+            urgency: Some(2),
+            timeout: 10_000,
+        }
+    );
+    server.join().unwrap();
+}